@@ -0,0 +1,21 @@
+//! Opening a store on an arbitrary data file must not panic, whatever it contains
+#![no_main]
+
+use knowsql_bitcask::{verify, BitCask};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let dir = std::env::temp_dir().join(format!("knowsql-fuzz-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("0.data"), data).unwrap();
+
+    let _ = verify::verify(&dir);
+
+    if let Ok(mut cask) = BitCask::open(dir.clone()) {
+        for key in cask.keys() {
+            let _ = cask.get(&key);
+        }
+        let _ = cask.stats();
+    }
+});
@@ -0,0 +1,20 @@
+//! Arbitrary client input must never panic the parser, and every parsed command must consume input
+#![no_main]
+
+use knowsql_parser::{parse_command, protocol::resp3, ParseError};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = data;
+    loop {
+        match parse_command(input) {
+            Ok((remaining, _)) | Err(ParseError::Command { remaining, .. }) => {
+                assert!(remaining.len() < input.len());
+                input = remaining;
+            }
+            Err(ParseError::Incomplete | ParseError::Protocol(_)) => break,
+        }
+    }
+
+    let _ = resp3::parse_data(data);
+});
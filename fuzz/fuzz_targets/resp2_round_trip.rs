@@ -0,0 +1,19 @@
+//! Whatever the RESP2 parser accepts must encode back to bytes that parse to the same data
+#![no_main]
+
+use knowsql_parser::protocol::resp2::parse_data;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((_, parsed)) = parse_data(data) else {
+        return;
+    };
+
+    let mut encoded = Vec::with_capacity(parsed.encoded_len());
+    parsed.write_to(&mut encoded).unwrap();
+    assert_eq!(encoded.len(), parsed.encoded_len());
+
+    let (remaining, reparsed) = parse_data(&encoded).expect("encoded data parses");
+    assert!(remaining.is_empty());
+    assert_eq!(parsed, reparsed);
+});
@@ -0,0 +1,524 @@
+//! Users and the commands and keys they may access, configured with Redis ACL rules
+//! https://redis.io/docs/management/security/acl/
+
+use knowsql_parser::command::Command;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// User connections start as, and that AUTH with only a password authenticates
+pub const DEFAULT_USER: &str = "default";
+
+/// What a user is not allowed to access
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Denied {
+    Command,
+    Key,
+}
+
+/// A user, created disabled and without access to any command or key
+#[derive(Clone, Debug, Default)]
+pub struct User {
+    enabled: bool,
+    /// Any password is accepted
+    nopass: bool,
+    /// Hex encoded SHA-256 of each password
+    passwords: BTreeSet<String>,
+    /// Glob patterns of the keys the user may access
+    keys: Vec<String>,
+    /// Commands the user may run, by subcommand for commands that have them
+    commands: BTreeSet<Unit>,
+    /// Command rules in the order they were applied, to describe the user
+    command_rules: Vec<String>,
+}
+
+/// A command, or one subcommand of a command with subcommands, that users are allowed to run
+type Unit = (&'static str, Option<&'static str>);
+
+/// Every unit of the command table with its ACL categories
+fn command_units() -> impl Iterator<Item = (Unit, &'static [&'static str])> {
+    Command::all_commands().iter().flat_map(|spec| {
+        let whole = spec
+            .subcommands
+            .is_empty()
+            .then_some(((spec.name, None), spec.categories));
+        let subcommands = spec
+            .subcommands
+            .iter()
+            .map(|sub| ((spec.name, Some(sub.name)), sub.categories));
+        whole.into_iter().chain(subcommands)
+    })
+}
+
+fn hash(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Rules with their plaintext passwords replaced by the hash of the password, to show them
+pub fn redact_passwords(rules: &str) -> String {
+    rules
+        .split_whitespace()
+        .map(|rule| match rule.split_at(rule.len().min(1)) {
+            (">", password) => format!("#{}", hash(password)),
+            ("<", password) => format!("!{}", hash(password)),
+            _ => rule.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Match a string against a Redis glob pattern, supporting `*`, `?`, `[a-z]`, `[^a]` and `\`
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern {
+        [] => string.is_empty(),
+        [b'*', rest @ ..] => {
+            // Consecutive stars match the same as one, skip them to avoid needless backtracking
+            let rest = &rest[rest.iter().take_while(|c| **c == b'*').count()..];
+            (0..=string.len()).any(|i| glob_match(rest, &string[i..]))
+        }
+        [b'?', rest @ ..] => !string.is_empty() && glob_match(rest, &string[1..]),
+        [b'[', rest @ ..] => {
+            let Some((c, string)) = string.split_first() else {
+                return false;
+            };
+            let (negate, mut rest) = match rest {
+                [b'^', rest @ ..] => (true, rest),
+                rest => (false, rest),
+            };
+
+            let mut matched = false;
+            loop {
+                rest = match rest {
+                    [] => return false,
+                    [b']', rest @ ..] => break glob_match(rest, string) && matched != negate,
+                    [b'\\', escaped, rest @ ..] => {
+                        matched |= escaped == c;
+                        rest
+                    }
+                    [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                        matched |= (*lo..=*hi).contains(c);
+                        rest
+                    }
+                    [other, rest @ ..] => {
+                        matched |= other == c;
+                        rest
+                    }
+                };
+            }
+        }
+        [b'\\', escaped, rest @ ..] | [escaped, rest @ ..] => {
+            string.first() == Some(escaped) && glob_match(rest, &string[1..])
+        }
+    }
+}
+
+impl User {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    /// Whether the user may connect without a password and access everything
+    pub fn is_unrestricted(&self) -> bool {
+        self.enabled
+            && self.nopass
+            && self.keys.iter().any(|pattern| pattern == "*")
+            && self.commands.len() == command_units().count()
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash(password)))
+    }
+
+    /// Whether the user may run the command on its keys
+    pub fn check(&self, command: &Command) -> Result<(), Denied> {
+        if !self
+            .commands
+            .contains(&(command.name(), command.subcommand()))
+        {
+            return Err(Denied::Command);
+        }
+
+        let allowed = |key: &str| {
+            self.keys
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+        };
+        match command.keys().iter().all(|key| allowed(key)) {
+            true => Ok(()),
+            false => Err(Denied::Key),
+        }
+    }
+
+    fn set_commands(&mut self, rule: &str, allow: bool, name: &str) -> Result<(), &'static str> {
+        let matching: Vec<Unit> = match name.strip_prefix('@') {
+            Some("all") => command_units().map(|(unit, _)| unit).collect(),
+            Some(category) => command_units()
+                .filter(|(_, categories)| categories.contains(&category))
+                .map(|(unit, _)| unit)
+                .collect(),
+            // `client` is every subcommand of CLIENT, `client|id` only one
+            None => {
+                let (name, subcommand) = match name.split_once('|') {
+                    Some((name, subcommand)) => (name, Some(subcommand)),
+                    None => (name, None),
+                };
+                command_units()
+                    .map(|(unit, _)| unit)
+                    .filter(|(command, sub)| {
+                        command.eq_ignore_ascii_case(name)
+                            && subcommand.map_or(true, |subcommand| {
+                                sub.is_some_and(|sub| sub.eq_ignore_ascii_case(subcommand))
+                            })
+                    })
+                    .collect()
+            }
+        };
+        if matching.is_empty() {
+            return Err("Unknown command or category name in ACL");
+        }
+
+        for unit in matching {
+            match allow {
+                true => self.commands.insert(unit),
+                false => self.commands.remove(&unit),
+            };
+        }
+        match rule {
+            "+@all" | "-@all" => self.command_rules.clear(),
+            _ => (),
+        }
+        if rule != "-@all" {
+            self.command_rules.push(rule.to_string());
+        }
+        Ok(())
+    }
+
+    /// Apply a single rule such as `on`, `>password`, `~key:*` or `+@read`
+    fn apply(&mut self, rule: &str) -> Result<(), &'static str> {
+        let lowercase = rule.to_ascii_lowercase();
+        let mut chars = rule.chars();
+        let (prefix, value) = (chars.next(), chars.as_str());
+
+        match lowercase.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allcommands" => self.set_commands("+@all", true, "@all")?,
+            "nocommands" => self.set_commands("-@all", false, "@all")?,
+            "reset" => *self = User::default(),
+            _ => match (prefix, value) {
+                (Some('>'), password) => {
+                    self.nopass = false;
+                    self.passwords.insert(hash(password));
+                }
+                (Some('<'), password) => {
+                    if !self.passwords.remove(&hash(password)) {
+                        return Err("no such password");
+                    }
+                }
+                (Some('#'), hash) => {
+                    if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters");
+                    }
+                    self.nopass = false;
+                    self.passwords.insert(hash.to_ascii_lowercase());
+                }
+                (Some('!'), hash) => {
+                    if !self.passwords.remove(&hash.to_ascii_lowercase()) {
+                        return Err("no such password");
+                    }
+                }
+                (Some('~'), pattern) => self.keys.push(pattern.to_string()),
+                (Some('+'), _) => self.set_commands(&lowercase, true, &lowercase[1..])?,
+                (Some('-'), _) => self.set_commands(&lowercase, false, &lowercase[1..])?,
+                _ => return Err("Syntax error"),
+            },
+        }
+        Ok(())
+    }
+
+    /// The rules that recreate the user from a new one
+    fn rules(&self) -> Vec<String> {
+        let mut rules = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            rules.push("nopass".to_string());
+        }
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.extend(self.keys.iter().map(|pattern| format!("~{}", pattern)));
+        if self.command_rules.first().map(String::as_str) != Some("+@all") {
+            rules.push("-@all".to_string());
+        }
+        rules.extend(self.command_rules.iter().cloned());
+        rules
+    }
+
+    /// Describe the user as the rules that recreate it, as listed by ACL LIST
+    fn describe(&self, name: &str) -> String {
+        format!("user {} {}", name, self.rules().join(" "))
+    }
+}
+
+/// Every user, by name
+#[derive(Clone, Debug)]
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    /// Hash of the password `requirepass` gave the default user
+    requirepass: Option<String>,
+}
+
+impl Acl {
+    /// Only the default user, which may access everything and needs `requirepass` when set
+    pub fn new(requirepass: Option<&str>) -> Acl {
+        let mut default = User::default();
+        for rule in ["on", "nopass", "allkeys", "allcommands"] {
+            default.apply(rule).expect("default rules are valid");
+        }
+
+        let mut acl = Acl {
+            users: BTreeMap::from([(DEFAULT_USER.to_string(), default)]),
+            requirepass: None,
+        };
+        acl.set_requirepass(requirepass);
+        acl
+    }
+
+    /// Replace the passwords of the default user with `requirepass`. Without one, only the
+    /// password an earlier `requirepass` set is removed, and the default user needs no password
+    /// once it has none left, passwords given to it by ACL rules are kept
+    pub fn set_requirepass(&mut self, requirepass: Option<&str>) {
+        let default = self.users.entry(DEFAULT_USER.to_string()).or_default();
+        match requirepass {
+            Some(password) => {
+                default.apply("resetpass").unwrap();
+                default.apply(&format!(">{}", password)).unwrap();
+                self.requirepass = Some(hash(password));
+            }
+            None => {
+                if let Some(hash) = self.requirepass.take() {
+                    default.passwords.remove(&hash);
+                    if default.passwords.is_empty() {
+                        default.apply("nopass").unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply rules to a user, creating it if it does not exist
+    ///   nothing is changed when a rule is invalid, the error names the rule
+    pub fn set_user(&mut self, name: &str, rules: &[impl AsRef<str>]) -> Result<(), String> {
+        let mut user = self.users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            let rule = rule.as_ref();
+            user.apply(rule)
+                .map_err(|err| format!("Error in ACL SETUSER modifier '{}': {}", rule, err))?;
+        }
+
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Whether the password is valid for the user, and the user is enabled
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|user| user.check_password(password))
+    }
+
+    /// Every user as a line of the `users` key, resetting the user so the line alone recreates
+    /// it. The default user is left out while it is the one `requirepass` alone makes
+    pub fn to_users(&self) -> Vec<String> {
+        let mut unchanged = Acl::new(None);
+        if let Some(hash) = &self.requirepass {
+            unchanged
+                .set_user(DEFAULT_USER, &[format!("#{}", hash)])
+                .unwrap();
+        }
+        let unchanged = unchanged.users[DEFAULT_USER].rules();
+
+        self.users
+            .iter()
+            .filter(|(name, user)| *name != DEFAULT_USER || user.rules() != unchanged)
+            .map(|(name, user)| format!("{} reset {}", name, user.rules().join(" ")))
+            .collect()
+    }
+
+    /// Every user, described as the rules that recreate it
+    pub fn list(&self) -> Vec<String> {
+        self.users
+            .iter()
+            .map(|(name, user)| user.describe(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use knowsql_parser::command::ClientCommand;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"h?llo", b"hallo"));
+        assert!(glob_match(b"h[a-e]llo", b"hello"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"**", b""));
+    }
+
+    #[test]
+    fn test_set_user() {
+        let mut acl = Acl::new(Some("secret"));
+        assert!(acl.authenticate(DEFAULT_USER, "secret"));
+        assert!(!acl.authenticate(DEFAULT_USER, "wrong"));
+
+        acl.set_user("alice", &["on", ">pw", "~cache:*", "+@read", "-keys"])
+            .unwrap();
+        let alice = acl.user("alice").unwrap();
+        assert!(acl.authenticate("alice", "pw"));
+        assert_eq!(alice.check(&Command::Get("cache:1".into())), Ok(()));
+        assert_eq!(
+            alice.check(&Command::Get("user:1".into())),
+            Err(Denied::Key)
+        );
+        assert_eq!(
+            alice.check(&Command::Set("cache:1".into(), b"v"[..].into())),
+            Err(Denied::Command)
+        );
+        assert_eq!(alice.check(&Command::Keys(None)), Err(Denied::Command));
+        assert_eq!(
+            acl.list()[0],
+            format!("user alice on #{} ~cache:* -@all +@read -keys", hash("pw"))
+        );
+
+        // An invalid rule leaves the user unchanged
+        assert!(acl.set_user("alice", &["off", "+@nope"]).is_err());
+        assert!(acl.authenticate("alice", "pw"));
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let mut acl = Acl::new(None);
+        acl.set_user("worker", &["on", "nopass", "+@connection", "-client|list"])
+            .unwrap();
+        let worker = acl.user("worker").unwrap();
+        let client = |command| Command::Client(command);
+        assert_eq!(worker.check(&client(ClientCommand::Id)), Ok(()));
+        assert_eq!(
+            worker.check(&client(ClientCommand::SetName("w".into()))),
+            Ok(())
+        );
+        assert_eq!(
+            worker.check(&client(ClientCommand::List)),
+            Err(Denied::Command)
+        );
+        assert_eq!(
+            worker.check(&client(ClientCommand::KillAddr("addr".into()))),
+            Ok(())
+        );
+
+        // Dangerous subcommands are left out of safe categories
+        acl.set_user("worker", &["-@all", "+@all", "-@dangerous"])
+            .unwrap();
+        let worker = acl.user("worker").unwrap();
+        assert_eq!(worker.check(&client(ClientCommand::GetName)), Ok(()));
+        assert_eq!(
+            worker.check(&client(ClientCommand::KillAddr("addr".into()))),
+            Err(Denied::Command)
+        );
+
+        acl.set_user("worker", &["-@all", "+client|getname"])
+            .unwrap();
+        let worker = acl.user("worker").unwrap();
+        assert_eq!(worker.check(&client(ClientCommand::GetName)), Ok(()));
+        assert_eq!(
+            worker.check(&client(ClientCommand::Id)),
+            Err(Denied::Command)
+        );
+        assert!(acl.set_user("worker", &["+client|nope"]).is_err());
+        assert!(acl.set_user("worker", &["+get|nope"]).is_err());
+        assert!(acl.user(DEFAULT_USER).unwrap().is_unrestricted());
+    }
+
+    #[test]
+    fn test_set_requirepass() {
+        let mut acl = Acl::new(Some("secret"));
+        acl.set_user(DEFAULT_USER, &[">configured"]).unwrap();
+
+        // Only the password of requirepass is removed
+        acl.set_requirepass(None);
+        assert!(!acl.authenticate(DEFAULT_USER, "secret"));
+        assert!(acl.authenticate(DEFAULT_USER, "configured"));
+        assert!(!acl.user(DEFAULT_USER).unwrap().is_nopass());
+
+        let mut acl = Acl::new(None);
+        acl.set_user(DEFAULT_USER, &["resetpass", ">configured"])
+            .unwrap();
+        acl.set_requirepass(None);
+        assert!(!acl.user(DEFAULT_USER).unwrap().is_nopass());
+
+        acl.set_requirepass(Some("secret"));
+        assert!(!acl.authenticate(DEFAULT_USER, "configured"));
+        acl.set_requirepass(None);
+        assert!(acl.user(DEFAULT_USER).unwrap().is_nopass());
+    }
+
+    #[test]
+    fn test_to_users() {
+        let mut acl = Acl::new(Some("secret"));
+        assert!(acl.to_users().is_empty());
+        assert_eq!(
+            redact_passwords("alice on >pw <old ~*"),
+            format!("alice on #{} !{} ~*", hash("pw"), hash("old"))
+        );
+
+        acl.set_user(
+            "alice",
+            &["on", ">pw", "~cache:*", "+@read", "-client|kill"],
+        )
+        .unwrap();
+        acl.set_user(DEFAULT_USER, &["-keys"]).unwrap();
+        let users = acl.to_users();
+        assert_eq!(
+            users[0],
+            format!(
+                "alice reset on #{} ~cache:* -@all +@read -client|kill",
+                hash("pw")
+            )
+        );
+
+        // The lines recreate every user on top of the users of the configuration
+        let mut recreated = Acl::new(Some("secret"));
+        recreated.set_user("alice", &["allcommands"]).unwrap();
+        for line in &users {
+            let mut rules = line.split_whitespace();
+            let name = rules.next().unwrap();
+            recreated
+                .set_user(name, &rules.collect::<Vec<_>>())
+                .unwrap();
+        }
+        assert_eq!(recreated.list(), acl.list());
+        assert_eq!(recreated.to_users(), users);
+    }
+}
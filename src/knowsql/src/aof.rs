@@ -0,0 +1,132 @@
+//! Append only file of every mutating command, encoded as RESP2
+
+use knowsql_parser::{command::Command, protocol::resp2::Data};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Write},
+    path::Path,
+};
+
+/// When the append only file is synced to disk
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppendFsync {
+    /// After every command
+    Always,
+    /// Once a second, from a background thread
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    file: File,
+    fsync: AppendFsync,
+    /// Whether commands were appended since the last sync
+    dirty: bool,
+}
+
+impl Aof {
+    pub fn open(path: &Path, fsync: AppendFsync) -> std::io::Result<Aof> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+        Ok(Aof {
+            file,
+            fsync,
+            dirty: false,
+        })
+    }
+
+    /// Append the command if it modifies the store. Commands are appended before they are
+    /// applied, so a write acknowledged to a client is always in the log
+    pub fn append(&mut self, command: &Command) -> std::io::Result<()> {
+        let entry = match command {
+            Command::Set(key, value) => {
+                let value = std::str::from_utf8(value)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "value is not valid utf8"))?;
+                Data::Array(vec![
+                    Data::BulkString("SET"),
+                    Data::BulkString(key),
+                    Data::BulkString(value),
+                ])
+            }
+            Command::Del(keys) => Data::Array(
+                std::iter::once("DEL")
+                    .chain(keys.iter().map(|key| key.as_ref()))
+                    .map(Data::BulkString)
+                    .collect(),
+            ),
+            Command::FlushAll => Data::Array(vec![Data::BulkString("FLUSHALL")]),
+            _ => return Ok(()),
+        };
+
+        let entry = entry.as_str().expect("bulk strings are always encodable");
+        self.file.write_all(entry.as_bytes())?;
+
+        self.dirty = true;
+        match self.fsync {
+            AppendFsync::Always => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_fsync(&mut self, fsync: AppendFsync) {
+        self.fsync = fsync;
+    }
+
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.dirty = false;
+        self.file.sync_data()
+    }
+
+    /// With `everysec`, a handle to sync when commands were appended since the last sync.
+    /// Syncing through the handle leaves the log free for appends meanwhile
+    pub fn pending_sync(&mut self) -> std::io::Result<Option<File>> {
+        if self.fsync != AppendFsync::Everysec || !self.dirty {
+            return Ok(None);
+        }
+
+        self.dirty = false;
+        self.file.try_clone().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let path = std::env::temp_dir().join(format!("knowsql-aof-{}.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut aof = Aof::open(&path, AppendFsync::Everysec).unwrap();
+        assert!(aof.pending_sync().unwrap().is_none());
+
+        aof.append(&Command::Set("a".into(), b"1".as_slice().into()))
+            .unwrap();
+        aof.append(&Command::Get("a".into())).unwrap();
+        aof.append(&Command::Del(vec!["a".into(), "b".into()]))
+            .unwrap();
+        aof.append(&Command::FlushAll).unwrap();
+        assert!(aof
+            .append(&Command::Set("b".into(), b"\xff".as_slice().into()))
+            .is_err());
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+              *3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n\
+              *1\r\n$8\r\nFLUSHALL\r\n"
+        );
+
+        assert!(aof.pending_sync().unwrap().is_some());
+        assert!(aof.pending_sync().unwrap().is_none());
+        aof.set_fsync(AppendFsync::Always);
+        aof.append(&Command::Set("a".into(), b"2".as_slice().into()))
+            .unwrap();
+        assert!(aof.pending_sync().unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! Registry of connected clients, listed and killed with the CLIENT command
+
+use knowsql_parser::command::KillFilter;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokio::sync::Notify;
+
+/// What a client did last and the traffic it caused, updated as it is served
+#[derive(Debug)]
+struct Activity {
+    name: Option<String>,
+    user: Option<String>,
+    /// Name of the last command, `NULL` before the first, like Redis
+    command: &'static str,
+    last_active: Instant,
+    /// Unparsed input held for the client, in bytes
+    query_buffer: usize,
+    /// Replies waiting to be written to the client, in bytes
+    output: usize,
+    net_in: u64,
+    net_out: u64,
+    commands: u64,
+}
+
+/// A connected client
+#[derive(Debug)]
+pub struct Client {
+    pub id: u64,
+    /// Address of the client, as logged in the span of its connection
+    pub addr: String,
+    created: Instant,
+    activity: Mutex<Activity>,
+    /// Notified when the client is killed, the connection closes once it has written its replies
+    killed: Notify,
+}
+
+impl Client {
+    pub fn name(&self) -> Option<String> {
+        self.activity.lock().unwrap().name.clone()
+    }
+
+    /// Name the client, `None` clears the name
+    pub fn set_name(&self, name: Option<&str>) {
+        self.activity.lock().unwrap().name = name.map(str::to_string);
+    }
+
+    pub fn set_user(&self, user: Option<&str>) {
+        self.activity.lock().unwrap().user = user.map(str::to_string);
+    }
+
+    /// Record that the client sent a command
+    pub fn record(&self, command: &'static str) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.command = command;
+        activity.last_active = Instant::now();
+        activity.commands += 1;
+    }
+
+    /// Record `read` bytes read from the client, leaving `query_buffer` bytes unparsed
+    pub fn record_input(&self, read: usize, query_buffer: usize) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.net_in += read as u64;
+        activity.query_buffer = query_buffer;
+    }
+
+    /// Record replies of `bytes` waiting to be written
+    pub fn record_output(&self, bytes: usize) {
+        self.activity.lock().unwrap().output = bytes;
+    }
+
+    /// Record `bytes` of the waiting replies were written
+    pub fn record_written(&self, bytes: usize) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.net_out += bytes as u64;
+        activity.output = activity.output.saturating_sub(bytes);
+    }
+
+    pub fn kill(&self) {
+        self.killed.notify_one();
+    }
+
+    /// Wait until the client is killed
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+
+    /// Describe the client as a line of CLIENT LIST
+    pub fn describe(&self) -> String {
+        let activity = self.activity.lock().unwrap();
+        format!(
+            "id={} addr={} name={} age={} idle={} qbuf={} omem={} user={} cmd={} tot-net-in={} tot-net-out={} tot-cmds={}",
+            self.id,
+            self.addr,
+            activity.name.as_deref().unwrap_or_default(),
+            self.created.elapsed().as_secs(),
+            activity.last_active.elapsed().as_secs(),
+            activity.query_buffer,
+            activity.output,
+            activity.user.as_deref().unwrap_or_default(),
+            activity.command.to_ascii_lowercase(),
+            activity.net_in,
+            activity.net_out,
+            activity.commands,
+        )
+    }
+
+    fn matches(&self, filter: &KillFilter) -> bool {
+        let activity = self.activity.lock().unwrap();
+        filter.id.map_or(true, |id| id == self.id)
+            && filter.addr.as_ref().map_or(true, |addr| *addr == self.addr)
+            && filter
+                .user
+                .as_ref()
+                .map_or(true, |user| activity.user.as_deref() == Some(user))
+    }
+}
+
+/// Every connected client, by id
+#[derive(Debug, Default)]
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+}
+
+impl Clients {
+    /// Add a client unless `max` clients are already connected. Ids start at 1 and are never
+    /// reused
+    pub fn register(&self, addr: String, max: usize) -> Option<Arc<Client>> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= max {
+            return None;
+        }
+
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            addr,
+            created: now,
+            activity: Mutex::new(Activity {
+                name: None,
+                user: None,
+                command: "NULL",
+                last_active: now,
+                query_buffer: 0,
+                output: 0,
+                net_in: 0,
+                net_out: 0,
+                commands: 0,
+            }),
+            killed: Notify::new(),
+        });
+
+        clients.insert(client.id, client.clone());
+        Some(client)
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Every client, oldest first
+    pub fn list(&self) -> Vec<Arc<Client>> {
+        self.clients.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Kill every client matching the filter, `me` is the calling client. Returns how many were
+    /// killed
+    pub fn kill(&self, filter: &KillFilter, me: u64) -> usize {
+        let clients = self.list();
+        let matching = clients
+            .iter()
+            .filter(|client| !(filter.skipme && client.id == me) && client.matches(filter));
+
+        let mut killed = 0;
+        for client in matching {
+            client.kill();
+            killed += 1;
+        }
+        killed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kill() {
+        let clients = Clients::default();
+        let me = clients.register("127.0.0.1:5000".to_string(), 2).unwrap();
+        let alice = clients.register("127.0.0.1:5001".to_string(), 2).unwrap();
+        assert!(clients.register("127.0.0.1:5002".to_string(), 2).is_none());
+        alice.set_user(Some("alice"));
+        alice.record("GET");
+        alice.record_input(14, 0);
+        alice.record_output(5);
+        alice.record_written(5);
+        assert!(alice
+            .describe()
+            .ends_with("user=alice cmd=get tot-net-in=14 tot-net-out=5 tot-cmds=1"));
+
+        let by_user = KillFilter {
+            user: Some("alice".into()),
+            skipme: true,
+            ..KillFilter::default()
+        };
+        assert_eq!(clients.kill(&by_user, me.id), 1);
+        let everyone = KillFilter {
+            skipme: true,
+            ..KillFilter::default()
+        };
+        assert_eq!(clients.kill(&everyone, me.id), 1);
+        let me_too = KillFilter {
+            addr: Some("127.0.0.1:5000".into()),
+            ..KillFilter::default()
+        };
+        assert_eq!(clients.kill(&me_too, me.id), 1);
+
+        clients.remove(alice.id);
+        assert_eq!(clients.list().len(), 1);
+    }
+}
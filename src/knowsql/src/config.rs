@@ -1,40 +1,611 @@
-use serde::Deserialize;
-use std::fs::read_to_string;
-use toml;
-use tracing::{debug, warn};
+use crate::{
+    acl::{glob_match, redact_passwords, Acl},
+    aof::AppendFsync,
+    tls::AuthClients,
+};
+use knowsql_parser::protocol::Limits;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::read_to_string,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+use tracing::{debug, level_filters::LevelFilter, warn};
 
-const DEFAULT_CONFIG_PATH: &'static str = "/etc/knowsql/config.toml";
+const DEFAULT_CONFIG_PATH: &str = "/etc/knowsql/config.toml";
+/// Prefix of environment variables overriding a key, such as `KNOWSQL_PORT`
+const ENV_PREFIX: &str = "KNOWSQL_";
 
-#[derive(Debug, Deserialize)]
+/// Every key and whether CONFIG SET and SIGHUP may change it while the server runs. The others
+/// are read-only, they are only read on startup
+const KEYS: &[(&str, bool)] = &[
+    ("data_dir", false),
+    ("bind", false),
+    ("port", false),
+    ("memcache_port", false),
+    ("unixsocket", false),
+    ("unixsocketperm", false),
+    ("tls_port", false),
+    ("tls_cert_file", false),
+    ("tls_key_file", false),
+    ("tls_ca_cert_file", false),
+    ("tls_auth_clients", false),
+    ("requirepass", true),
+    ("users", false),
+    ("log_level", true),
+    ("read_cache_size", false),
+    ("merge_dead_ratio", true),
+    ("merge_min_dead_bytes", true),
+    ("merge_interval", true),
+    ("appendonly", false),
+    ("appendfilename", false),
+    ("appendfsync", true),
+    ("proto_max_bulk_len", true),
+    ("proto_max_array_len", true),
+    ("proto_max_depth", true),
+    ("proto_max_inline_len", true),
+    ("client_query_buffer_limit", true),
+    ("client_output_buffer_hard_limit", true),
+    ("client_output_buffer_soft_limit", true),
+    ("client_output_buffer_soft_seconds", true),
+    ("client_write_timeout", true),
+    ("maxclients", true),
+    ("timeout", true),
+    ("shutdown_timeout", true),
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub data_dir: String,
-    pub port: usize,
+    /// Addresses to listen on, IPv4 or IPv6
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    /// Port to serve the memcached text protocol on, not served when unset
+    pub memcache_port: Option<u16>,
+    /// Path of a Unix domain socket to serve RESP on, not served when unset
+    pub unixsocket: Option<PathBuf>,
+    /// Permissions of the Unix domain socket, left to the umask when unset
+    pub unixsocketperm: Option<u32>,
+    /// Port to serve RESP over TLS on, not served when unset
+    pub tls_port: Option<u16>,
+    /// PEM encoded certificate chain presented to clients
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM encoded private key of the certificate
+    pub tls_key_file: Option<PathBuf>,
+    /// PEM encoded CA certificates client certificates are verified against
+    pub tls_ca_cert_file: Option<PathBuf>,
+    pub tls_auth_clients: AuthClients,
+    /// Password of the default user, which needs none when unset or empty
+    pub requirepass: Option<String>,
+    /// ACL users, each the user name followed by its rules like `ACL SETUSER`
+    pub users: Vec<String>,
+    /// Most verbose level logged: off, error, warn, info, debug or trace
+    pub log_level: String,
+    /// Number of values held in the read cache, 0 disables the cache
+    pub read_cache_size: usize,
+    /// Merge once this fraction of the data on disk belongs to overwritten or deleted entries
+    pub merge_dead_ratio: f64,
+    /// Never merge while fewer than this many bytes can be reclaimed
+    pub merge_min_dead_bytes: u64,
+    /// Seconds between checks of whether a merge is due
+    pub merge_interval: u64,
+    /// Log every mutating command to an append only file
+    pub appendonly: bool,
+    /// Path of the append only file, relative paths are within `data_dir`
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// Largest bulk string a client may send, in bytes
+    pub proto_max_bulk_len: usize,
+    /// Most elements of an array a client may send
+    pub proto_max_array_len: usize,
+    /// Deepest nesting of arrays a client may send
+    pub proto_max_depth: usize,
+    /// Longest inline command a client may send, in bytes
+    pub proto_max_inline_len: usize,
+    /// Most unparsed input held for a client, in bytes. Bounds requests split into many parts
+    pub client_query_buffer_limit: usize,
+    /// Most replies held for a client, in bytes. It is disconnected beyond this, 0 is no limit
+    pub client_output_buffer_hard_limit: usize,
+    /// Replies beyond this many bytes must be read within `client_output_buffer_soft_seconds`
+    /// or the client is disconnected, 0 is no limit
+    pub client_output_buffer_soft_limit: usize,
+    pub client_output_buffer_soft_seconds: u64,
+    /// Seconds a client with replies waiting may read none of them before it is disconnected,
+    /// 0 waits forever
+    pub client_write_timeout: u64,
+    /// Most clients connected at once, further connections are refused
+    pub maxclients: usize,
+    /// Seconds a client may be idle before it is disconnected, 0 never disconnects idle clients
+    pub timeout: u64,
+    /// Seconds to wait for clients to finish on shutdown before exiting regardless
+    pub shutdown_timeout: u64,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             data_dir: "./data".to_string(),
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
             port: 2288,
+            memcache_port: None,
+            unixsocket: None,
+            unixsocketperm: None,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: AuthClients::No,
+            requirepass: None,
+            users: Vec::new(),
+            log_level: "info".to_string(),
+            read_cache_size: 0,
+            merge_dead_ratio: 0.5,
+            merge_min_dead_bytes: 64 * 1024 * 1024,
+            merge_interval: 60,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::Everysec,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            proto_max_array_len: 1024 * 1024,
+            proto_max_depth: 32,
+            proto_max_inline_len: 64 * 1024,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            client_output_buffer_hard_limit: 1024 * 1024 * 1024,
+            client_output_buffer_soft_limit: 256 * 1024 * 1024,
+            client_output_buffer_soft_seconds: 60,
+            client_write_timeout: 60,
+            maxclients: 10000,
+            timeout: 0,
+            shutdown_timeout: 10,
+        }
+    }
+}
+
+impl Config {
+    /// Protocol limits enforced on client requests
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_array_len: self.proto_max_array_len,
+            max_depth: self.proto_max_depth,
+            max_inline_len: self.proto_max_inline_len,
+        }
+    }
+
+    /// Password of the default user, an empty `requirepass` is the same as none
+    pub fn requirepass(&self) -> Option<&str> {
+        self.requirepass
+            .as_deref()
+            .filter(|password| !password.is_empty())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+
+    /// Users with `requirepass` applied to the default user, then the rules of `users`
+    pub fn acl(&self) -> Result<Acl, String> {
+        let mut acl = Acl::new(self.requirepass());
+        for line in &self.users {
+            let mut rules = line.split_whitespace();
+            let name = rules.next().ok_or("ACL user without a name")?;
+            acl.set_user(name, &rules.collect::<Vec<_>>())
+                .map_err(|err| format!("user {}: {}", name, err))?;
+        }
+        Ok(acl)
+    }
+}
+
+/// Command line arguments of the server
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Path given with `--config`, overriding `KNOWSQL_CONFIG`
+    pub config_path: Option<String>,
+    /// Validate the configuration and exit
+    pub check_config: bool,
+    pub help: bool,
+    /// `--key value` pairs overriding keys of the configuration, in order
+    pub overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    /// Parse arguments, without the program name. Keys may be given as `--data-dir` or
+    /// `--data_dir`, and their value as the next argument or after `=`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", arg));
+            };
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (flag, None),
+            };
+
+            match flag {
+                "check-config" | "check_config" if value.is_none() => parsed.check_config = true,
+                "help" if value.is_none() => parsed.help = true,
+                _ => {
+                    let value = value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| format!("missing value for '--{}'", flag))?;
+                    match flag {
+                        "config" => parsed.config_path = Some(value),
+                        _ => parsed.overrides.push((flag.replace('-', "_"), value)),
+                    }
+                }
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Set a key from a string given on the command line or in the environment
+///   the value is TOML when that is valid for the key, such as `7000` or `["::1"]`, then a string,
+///   then a list split on commas, so paths, passwords and lists need no quotes
+fn set_override(table: &mut Table, key: &str, value: &str) -> Result<(), String> {
+    let parsed = format!("value = {}", value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"));
+
+    let mut error = None;
+    let list = value
+        .split(',')
+        .map(|item| Value::String(item.trim().to_string()))
+        .collect();
+    let candidates = parsed
+        .into_iter()
+        .chain([Value::String(value.to_string()), Value::Array(list)]);
+
+    for value in candidates {
+        let single = Table::from_iter([(key.to_string(), value.clone())]);
+        match Config::deserialize(Value::Table(single)) {
+            Ok(_) => {
+                table.insert(key.to_string(), value);
+                return Ok(());
+            }
+            Err(err) => {
+                error.get_or_insert(err.message().to_string());
+            }
+        }
+    }
+    Err(error.expect("a string value is always tried"))
+}
+
+/// Path of the configuration file, and whether it was given rather than the default
+pub fn path(args: &Args) -> (String, bool) {
+    match args
+        .config_path
+        .clone()
+        .or_else(|| std::env::var("KNOWSQL_CONFIG").ok())
+    {
+        Some(path) => (path, true),
+        None => (DEFAULT_CONFIG_PATH.to_string(), false),
+    }
+}
+
+/// Load the configuration file, then apply `KNOWSQL_*` environment variables and `--key value`
+/// arguments over it, in that order
+///   the file is `--config`, `KNOWSQL_CONFIG` or DEFAULT_CONFIG_PATH, only the default may be
+///   missing in which case every key has its default
+pub fn load(args: &Args) -> Result<Config, String> {
+    let (path, explicit) = path(args);
+
+    let mut table = match read_to_string(&path) {
+        Ok(config) => {
+            debug!(path = path, "loading configuration");
+            config
+                .parse::<Table>()
+                .map_err(|err| format!("{}: {}", path, err))?
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            warn!(path = path, "configuration not found, using defaults");
+            Table::new()
+        }
+        Err(err) => return Err(format!("{}: {}", path, err)),
+    };
+
+    let mut env: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != "KNOWSQL_CONFIG")
+        .collect();
+    env.sort();
+    for (name, value) in env {
+        let key = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+        set_override(&mut table, &key, &value).map_err(|err| format!("{}: {}", name, err))?;
+    }
+    for (key, value) in &args.overrides {
+        set_override(&mut table, key, value).map_err(|err| format!("--{}: {}", key, err))?;
+    }
+
+    Config::deserialize(Value::Table(table)).map_err(|err| format!("{}: {}", path, err))
+}
+
+impl Config {
+    /// Check the values of keys that depend on each other or have a narrower range than their
+    /// type, so a configuration that loads is one the server can start with
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.merge_dead_ratio) {
+            return Err("merge_dead_ratio must be between 0 and 1".to_string());
+        }
+        if self.merge_interval == 0 {
+            return Err("merge_interval must be at least 1".to_string());
+        }
+        if self.unixsocketperm.is_some_and(|mode| mode > 0o777) {
+            return Err("unixsocketperm must be at most 0o777".to_string());
+        }
+        if self.tls_port.is_some() && (self.tls_cert_file.is_none() || self.tls_key_file.is_none())
+        {
+            return Err("tls_port requires tls_cert_file and tls_key_file".to_string());
+        }
+        let limits = [
+            ("proto_max_bulk_len", self.proto_max_bulk_len),
+            ("proto_max_array_len", self.proto_max_array_len),
+            ("proto_max_depth", self.proto_max_depth),
+            ("proto_max_inline_len", self.proto_max_inline_len),
+            ("client_query_buffer_limit", self.client_query_buffer_limit),
+            ("maxclients", self.maxclients),
+        ];
+        if let Some((key, _)) = limits.iter().find(|(_, limit)| *limit == 0) {
+            return Err(format!("{} must be at least 1", key));
         }
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            return Err(
+                "log_level must be one of off, error, warn, info, debug or trace".to_string(),
+            );
+        }
+
+        self.acl().map(|_| ())
     }
 }
 
-/// Use environment variable KNOWSQL_CONFIG or DEFAULT_CONFIG_PATH to load the config
-///   if it doesn't exist, use default config
-pub fn get_config() -> Config {
-    let config_path = std::env::var("KNOWSQL_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+/// A value as CONFIG GET reports it, lists are joined with commas so they can be set again
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
 
-    if let Ok(config) = read_to_string(config_path.clone()) {
-        debug!(path = config_path, "loading configuration");
-        if let Ok(parsed_config) = toml::from_str(&config) {
-            return parsed_config;
+/// Keys are named with `_`, CONFIG accepts `-` like Redis and ignores case
+fn normalize_key(key: &str) -> String {
+    key.to_ascii_lowercase().replace('-', "_")
+}
+
+impl Config {
+    /// Every set key and its value, unset keys are left out
+    fn to_table(&self) -> Table {
+        match Value::try_from(self) {
+            Ok(Value::Table(table)) => table,
+            _ => unreachable!("the configuration serializes to a table"),
+        }
+    }
+
+    /// Every key matching one of the glob patterns with its value, unset keys are empty
+    pub fn get(&self, patterns: &[impl AsRef<str>]) -> Vec<(&'static str, String)> {
+        let patterns: Vec<String> = patterns.iter().map(|p| normalize_key(p.as_ref())).collect();
+        let mut table = self.to_table();
+        // Passwords of users are shown by their hash, like ACL LIST
+        let users = self.users.iter().map(|line| redact_passwords(line).into());
+        table.insert("users".to_string(), Value::Array(users.collect()));
+
+        KEYS.iter()
+            .filter(|(key, _)| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+            })
+            .map(|(key, _)| (*key, table.get(*key).map(display).unwrap_or_default()))
+            .collect()
+    }
+
+    /// The configuration with keys set from strings like command line overrides, as CONFIG SET
+    /// does. Fails without changing anything when a key is read-only or a value is invalid
+    pub fn set(&self, pairs: &[(impl AsRef<str>, impl AsRef<str>)]) -> Result<Config, String> {
+        let mut table = self.to_table();
+        for (key, value) in pairs {
+            let key = normalize_key(key.as_ref());
+            let failed = |err: &str| {
+                format!(
+                    "CONFIG SET failed (possibly related to argument '{}') - {}",
+                    key, err
+                )
+            };
+            match KEYS.iter().find(|(name, _)| *name == key) {
+                None => {
+                    return Err(format!(
+                        "Unknown option or number of args for CONFIG SET - '{}'",
+                        key
+                    ))
+                }
+                Some((_, false)) => return Err(failed("can't set read-only config")),
+                Some((_, true)) => {
+                    set_override(&mut table, &key, value.as_ref()).map_err(|err| failed(&err))?
+                }
+            }
         }
+
+        let config =
+            Config::deserialize(Value::Table(table)).map_err(|err| err.message().to_string())?;
+        config
+            .validate()
+            .map_err(|err| format!("CONFIG SET failed - {}", err))?;
+        Ok(config)
     }
 
-    warn!(
-        path = config_path,
-        "unable to read configuration. using defaults."
-    );
-    Config::default()
+    /// The configuration with the runtime keys of `loaded`, to reload it on SIGHUP. Also returns
+    /// the read-only keys `loaded` changes, which keep their running value
+    pub fn reload(&self, loaded: &Config) -> (Config, Vec<&'static str>) {
+        let (mut table, loaded) = (self.to_table(), loaded.to_table());
+        let mut ignored = Vec::new();
+
+        for (key, runtime) in KEYS {
+            match (runtime, loaded.get(*key)) {
+                (true, Some(value)) => {
+                    table.insert(key.to_string(), value.clone());
+                }
+                (true, None) => {
+                    table.remove(*key);
+                }
+                (false, value) if value != table.get(*key) => ignored.push(*key),
+                (false, _) => (),
+            }
+        }
+
+        let config = Config::deserialize(Value::Table(table))
+            .expect("keys of valid configurations form a valid configuration");
+        (config, ignored)
+    }
+
+    /// Write the configuration to the file at `path`, like CONFIG REWRITE. Keys in the file are
+    /// updated in place so comments are kept, other keys are added unless they have their default
+    pub fn rewrite(&self, path: &Path) -> Result<(), String> {
+        let contents = match read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err("The server is running without a config file".to_string())
+            }
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        };
+        let mut document = contents
+            .parse::<toml_edit::DocumentMut>()
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        let (table, defaults) = (self.to_table(), Config::default().to_table());
+        for (key, _) in KEYS {
+            match table.get(*key) {
+                Some(value) if document.contains_key(key) || defaults.get(*key) != Some(value) => {
+                    let value = value
+                        .to_string()
+                        .parse::<toml_edit::Value>()
+                        .expect("TOML values are valid TOML");
+                    document[key] = toml_edit::value(value);
+                }
+                Some(_) => (),
+                None => {
+                    document.remove(key);
+                }
+            }
+        }
+
+        // Write a copy then rename it over the file, so a failed write leaves the file intact
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, document.to_string())
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_overrides() {
+        let args = Args::parse(
+            [
+                "--port",
+                "7000",
+                "--data-dir=/tmp/data",
+                "--requirepass",
+                "1234",
+                "--check-config",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert!(args.check_config);
+
+        let mut table = Table::new();
+        for (key, value) in &args.overrides {
+            set_override(&mut table, key, value).unwrap();
+        }
+        set_override(&mut table, "bind", "::1, 127.0.0.1").unwrap();
+        let config = Config::deserialize(Value::Table(table)).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.data_dir, "/tmp/data");
+        assert_eq!(config.requirepass.as_deref(), Some("1234"));
+        assert_eq!(
+            config.bind,
+            vec![
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            ]
+        );
+
+        let mut table = Table::new();
+        assert!(set_override(&mut table, "port", "70000").is_err());
+        assert!(set_override(&mut table, "prot", "7000").is_err());
+        assert!("prot = 7000"
+            .parse::<Table>()
+            .map(|table| Config::deserialize(Value::Table(table)).is_err())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_runtime_changes() {
+        let config = Config::default();
+        assert!(config
+            .to_table()
+            .keys()
+            .all(|key| KEYS.iter().any(|(name, _)| name == key)));
+
+        let config = config.set(&[("appendfsync", "always")]).unwrap();
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(
+            config.get(&["APPEND*"]),
+            vec![
+                ("appendonly", "false".to_string()),
+                ("appendfilename", "appendonly.aof".to_string()),
+                ("appendfsync", "always".to_string()),
+            ]
+        );
+        assert_eq!(config.get(&["bind"]), vec![("bind", "0.0.0.0".to_string())]);
+        assert_eq!(config.get(&["tls-port"]), vec![("tls_port", String::new())]);
+        let mut with_users = config.clone();
+        with_users.users = vec!["alice on >pw ~*".to_string(), "bob off".to_string()];
+        assert_eq!(
+            with_users.get(&["users"]),
+            vec![(
+                "users",
+                "alice on #30c952fab122c3f9759f02a6d95c3758b246b4fee239957b2d4fee46e26170c4 ~*,bob off"
+                    .to_string()
+            )]
+        );
+        assert!(config.set(&[("port", "7000")]).is_err());
+        assert!(config.set(&[("prot", "7000")]).is_err());
+        assert!(config.set(&[("log-level", "loud")]).is_err());
+
+        let loaded = config.set(&[("log_level", "debug")]).unwrap();
+        let mut loaded = loaded.set(&[("appendfsync", "no")]).unwrap();
+        loaded.port = 7000;
+        let (reloaded, ignored) = config.reload(&loaded);
+        assert_eq!(reloaded.log_level, "debug");
+        assert_eq!(reloaded.appendfsync, AppendFsync::No);
+        assert_eq!(reloaded.port, 2288);
+        assert_eq!(ignored, vec!["port"]);
+
+        let path = std::env::temp_dir().join(format!("knowsql-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "# the port\nport = 7000\n").unwrap();
+        let config: Config = toml::from_str(&read_to_string(&path).unwrap()).unwrap();
+        config
+            .set(&[("appendfsync", "always")])
+            .unwrap()
+            .rewrite(&path)
+            .unwrap();
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            "# the port\nport = 7000\nappendfsync = \"always\"\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,53 @@
+//! Sections of the INFO command reply
+
+use knowsql_bitcask::BitCask;
+use std::fmt::Write;
+
+/// Render the INFO reply, optionally limited to a single section
+pub fn info(bitcask: &BitCask, section: Option<&str>) -> String {
+    let mut out = String::new();
+    let wanted = |name: &str| match section {
+        None => true,
+        Some(section) => section.eq_ignore_ascii_case(name) || section.eq_ignore_ascii_case("all"),
+    };
+
+    if wanted("cache") {
+        let cache = bitcask.cache_stats();
+        let stats = cache.unwrap_or_default();
+
+        writeln!(out, "# Cache\r").unwrap();
+        writeln!(out, "read_cache_enabled:{}\r", cache.is_some() as u8).unwrap();
+        writeln!(out, "read_cache_capacity:{}\r", stats.capacity).unwrap();
+        writeln!(out, "read_cache_keys:{}\r", stats.len).unwrap();
+        writeln!(out, "read_cache_hits:{}\r", stats.hits).unwrap();
+        writeln!(out, "read_cache_misses:{}\r", stats.misses).unwrap();
+    }
+
+    if wanted("storage") {
+        let stats = bitcask.stats();
+
+        writeln!(out, "# Storage\r").unwrap();
+        writeln!(out, "keys:{}\r", stats.keys).unwrap();
+        writeln!(out, "key_dir_bytes:{}\r", stats.key_dir_bytes).unwrap();
+        writeln!(out, "total_bytes:{}\r", stats.total_bytes()).unwrap();
+        writeln!(out, "live_bytes:{}\r", stats.live_bytes()).unwrap();
+        writeln!(out, "dead_bytes:{}\r", stats.dead_bytes()).unwrap();
+        writeln!(out, "last_merge:{}\r", stats.last_merge.unwrap_or(-1)).unwrap();
+        writeln!(
+            out,
+            "write_amplification:{:.2}\r",
+            stats.write_amplification
+        )
+        .unwrap();
+        for segment in &stats.segments {
+            writeln!(
+                out,
+                "segment{}:total={},live={},dead={}\r",
+                segment.file_id, segment.total_bytes, segment.live_bytes, segment.dead_bytes
+            )
+            .unwrap();
+        }
+    }
+
+    out
+}
@@ -0,0 +1,140 @@
+//! Sockets clients connect to, over TCP or a Unix domain socket
+
+use std::{
+    fs::{DirBuilder, Permissions},
+    future::Future,
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+};
+
+/// A socket accepting client connections
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Wait for a client, returning its stream and its address for logging
+    fn accept_client(&self) -> impl Future<Output = io::Result<(Self::Stream, String)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept_client(&self) -> io::Result<(Self::Stream, String)> {
+        let (stream, addr) = self.accept().await?;
+        Ok((stream, addr.to_string()))
+    }
+}
+
+/// Unix domain socket, removed from the filesystem when dropped
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Bind to `path`, replacing a socket left behind by an earlier run but no other kind of file
+    ///   permissions are left to the umask when `mode` is unset
+    pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixSocket> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "path exists and is not a socket",
+                ))
+            }
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+
+        // The socket is created in a directory only we can enter and moved into place once its
+        // permissions are set, so it is never reachable with the permissions of the umask
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let mut private_dir = path.to_path_buf();
+        private_dir.set_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            std::process::id()
+        ));
+        DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+        let bound = bind_in(&private_dir, path, mode);
+        let _ = std::fs::remove_dir_all(&private_dir);
+
+        Ok(UnixSocket {
+            listener: bound?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// Bind a socket within `dir`, set its permissions and move it to `path`
+fn bind_in(dir: &Path, path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    let staged = dir.join("socket");
+    let listener = UnixListener::bind(&staged)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+    }
+    std::fs::rename(&staged, path)?;
+    Ok(listener)
+}
+
+impl Listener for UnixSocket {
+    type Stream = tokio::net::UnixStream;
+
+    // Clients connect from unnamed sockets, so they are told apart by the socket they used
+    async fn accept_client(&self) -> io::Result<(Self::Stream, String)> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok((stream, format!("unix:{}", self.path.display())))
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn test_bind_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("knowsql-unixsocket-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("knowsql.sock");
+
+        // Only a socket is replaced
+        std::fs::write(&path, "data").unwrap();
+        assert!(UnixSocket::bind(&path, None).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        std::fs::remove_file(&path).unwrap();
+
+        let socket = UnixSocket::bind(&path, Some(0o600)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, addr) = socket.accept_client().await.unwrap();
+        assert_eq!(addr, format!("unix:{}", path.display()));
+
+        // A socket left behind by an earlier run, which never removed it, is replaced
+        std::mem::forget(socket);
+        let socket = UnixSocket::bind(&path, Some(0o660)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o660);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        socket.accept_client().await.unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+        // No staging directory is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
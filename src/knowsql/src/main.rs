@@ -1,193 +1,584 @@
+mod acl;
+mod aof;
+mod clients;
 mod config;
+mod info;
+mod listener;
+mod memcache;
+mod resp;
+mod tls;
 
+use acl::Acl;
+use aof::Aof;
+use clients::{Client, Clients};
+use config::Config;
 use knowsql_bitcask::BitCask;
-use knowsql_parser::{
-    command::{Command, SubCommand},
-    parse_command,
-    protocol::resp2::Data,
-};
-use regex::Regex;
+use knowsql_parser::command::{Command, ShutdownMode};
+use listener::{Listener, UnixSocket};
+use tokio_rustls::TlsAcceptor;
 
 use std::{
-    io::{BufWriter, Read, Write},
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
 };
-use tracing::{debug, error, info, span, trace, Level};
+use tracing::{error, info, level_filters::LevelFilter, span, trace, warn, Instrument, Level};
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry};
+
+const USAGE: &str = "usage: knowsql [--config <file>] [--check-config] [--<key> <value> ...]
+
+options:
+    --config <file>
+        configuration file, instead of KNOWSQL_CONFIG or /etc/knowsql/config.toml
+    --check-config
+        validate the configuration and exit
+    --<key> <value>
+        override a key of the configuration, such as --port 7000 or --data-dir /tmp/data
+        keys can also be overridden with environment variables, such as KNOWSQL_PORT=7000";
+
+/// Longest a client may take to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-fn main() {
-    tracing_subscriber::fmt::init();
-    let config = config::get_config();
+/// State shared by every connection
+pub struct State {
+    pub bitcask: Mutex<BitCask>,
+    pub aof: Option<Mutex<Aof>>,
+    pub acl: RwLock<Acl>,
+    pub clients: Clients,
+    /// The running configuration, runtime keys are read from it as they are used
+    pub config: RwLock<Config>,
+    /// File CONFIG REWRITE writes the configuration to
+    pub config_path: PathBuf,
+    /// Changes the level logged at runtime
+    pub log_filter: reload::Handle<LevelFilter, Registry>,
+    pub started: Instant,
+    /// Set once the server is shutting down, by a signal or the SHUTDOWN command
+    pub shutdown: watch::Sender<Option<ShutdownMode>>,
+}
+
+impl State {
+    /// Append a command that modifies the store to the append only file, if enabled. Commands
+    /// are logged before they are applied, so a write acknowledged to a client is always logged
+    pub fn log(&self, command: &Command) -> io::Result<()> {
+        match &self.aof {
+            Some(aof) => aof.lock().unwrap().append(command),
+            None => Ok(()),
+        }
+    }
 
-    let bitcask = BitCask::open(config.data_dir.clone().into()).expect("failed to open bitcask");
-    let bitcask = Arc::new(Mutex::new(bitcask));
+    /// Replace the configuration with the one `change` makes from it, applying the runtime keys
+    /// that are not read as they are used
+    pub fn reconfigure(
+        &self,
+        change: impl FnOnce(&Config) -> Result<Config, String>,
+    ) -> Result<(), String> {
+        let mut config = self.config.write().unwrap();
+        let changed = change(&config)?;
+
+        if changed.log_level != config.log_level {
+            if let Err(err) = self.log_filter.reload(changed.log_level()) {
+                error!(err = %err, "failed to change log level");
+            }
+        }
+        if changed.appendfsync != config.appendfsync {
+            if let Some(aof) = &self.aof {
+                aof.lock().unwrap().set_fsync(changed.appendfsync);
+            }
+        }
+        if changed.requirepass() != config.requirepass() {
+            self.acl
+                .write()
+                .unwrap()
+                .set_requirepass(changed.requirepass());
+        }
+
+        *config = changed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl State {
+    /// State around a fresh data directory named after the test, opening the append only file
+    /// when the configuration enables it
+    pub fn for_test(name: &str, mut config: Config) -> State {
+        let dir = std::env::temp_dir().join(format!("knowsql-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        config.data_dir = dir.display().to_string();
+
+        let bitcask = BitCask::open(dir.clone()).unwrap();
+        let aof = config.appendonly.then(|| {
+            let path = dir.join(&config.appendfilename);
+            Mutex::new(Aof::open(&path, config.appendfsync).unwrap())
+        });
+
+        State {
+            bitcask: Mutex::new(bitcask),
+            aof,
+            acl: RwLock::new(config.acl().unwrap()),
+            clients: Clients::default(),
+            config_path: dir.join("config.toml"),
+            config: RwLock::new(config),
+            log_filter: reload::Layer::new(LevelFilter::INFO).1,
+            started: Instant::now(),
+            shutdown: watch::channel(None).0,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // Logs at info until the configuration is loaded, which may change the level
+    let (log_filter, log_handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt::layer())
+        .init();
+
+    let args = match config::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    let config = match config::load(&args).and_then(|config| config.validate().map(|_| config)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("invalid configuration: {}", message);
+            return ExitCode::from(2);
+        }
+    };
+    if args.check_config {
+        if let Err(err) = tls_port_acceptor(&config) {
+            eprintln!("invalid configuration: {}", err);
+            return ExitCode::from(2);
+        }
+        println!("configuration is valid");
+        return ExitCode::SUCCESS;
+    }
+    if let Err(err) = log_handle.reload(config.log_level()) {
+        error!(err = %err, "failed to set log level");
+    }
+
+    let bitcask = match BitCask::open(config.data_dir.clone().into()) {
+        Ok(bitcask) => bitcask.with_read_cache(config.read_cache_size),
+        Err(err) => {
+            error!(data_dir = config.data_dir, err = %err, "failed to open bitcask");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let aof = if config.appendonly {
+        let path = Path::new(&config.data_dir).join(&config.appendfilename);
+        match Aof::open(&path, config.appendfsync) {
+            Ok(aof) => Some(Mutex::new(aof)),
+            Err(err) => {
+                error!(path = %path.display(), err = %err, "failed to open append only file");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let state = Arc::new(State {
+        bitcask: Mutex::new(bitcask),
+        aof,
+        acl: RwLock::new(config.acl().expect("users are checked by Config::validate")),
+        clients: Clients::default(),
+        config: RwLock::new(config.clone()),
+        config_path: config::path(&args).0.into(),
+        log_filter: log_handle,
+        started: Instant::now(),
+        shutdown: watch::channel(None).0,
+    });
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => info!("received SIGTERM"),
+                _ = interrupt.recv() => info!("received SIGINT"),
+            }
+            state.shutdown.send_replace(Some(ShutdownMode::Save));
+        });
+    }
+
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("received SIGHUP, reloading configuration");
+                reload_config(&state, &args);
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        // Merging is slow blocking io, it gets its own thread rather than a runtime worker
+        std::thread::spawn(move || loop {
+            let interval = state.config.read().unwrap().merge_interval;
+            std::thread::sleep(Duration::from_secs(interval));
+
+            let config = state.config.read().unwrap();
+            let (ratio, min_bytes) = (config.merge_dead_ratio, config.merge_min_dead_bytes);
+            drop(config);
+            merge_if_due(&state.bitcask, ratio, min_bytes);
+        });
+    }
+
+    if state.aof.is_some() {
+        let state = state.clone();
+        // Like Redis, everysec syncs from a background thread so a slow disk does not stall writes
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            let aof = state
+                .aof
+                .as_ref()
+                .expect("only spawned with an append only file");
+            let pending = aof.lock().unwrap().pending_sync();
+            if let Err(err) = pending.and_then(|file| file.map_or(Ok(()), |file| file.sync_data()))
+            {
+                error!(err = %err, "failed to sync append only file");
+            }
+        });
+    }
 
     info!(
         port = config.port,
+        memcache_port = config.memcache_port,
         data_dir = config.data_dir,
         "starting knowsql server"
     );
 
-    let addr = format!("0.0.0.0:{}", config.port);
-    let listener = match TcpListener::bind(addr.clone()) {
-        Ok(listener) => listener,
+    // Like Redis, port 0 disables the plaintext port
+    let listeners = match config.port {
+        0 => Vec::new(),
+        port => match bind_all(&config.bind, port).await {
+            Some(listeners) => listeners,
+            None => return ExitCode::FAILURE,
+        },
+    };
+    let memcache_listeners = match config.memcache_port {
+        Some(port) => match bind_all(&config.bind, port).await {
+            Some(listeners) => listeners,
+            None => return ExitCode::FAILURE,
+        },
+        None => Vec::new(),
+    };
+    let tls_listeners = match tls_port_acceptor(&config) {
+        Ok(Some((port, acceptor))) => match bind_all(&config.bind, port).await {
+            Some(listeners) => Some((acceptor, listeners)),
+            None => return ExitCode::FAILURE,
+        },
+        Ok(None) => None,
         Err(err) => {
-            error!(addr = addr, err = %err, "failed to create TcpListener");
+            error!(err = %err, "failed to configure TLS");
+            return ExitCode::FAILURE;
+        }
+    };
+    let unix_socket = match &config.unixsocket {
+        Some(path) => match UnixSocket::bind(path, config.unixsocketperm) {
+            Ok(socket) => {
+                info!(path = %path.display(), "listening on unix socket");
+                Some(socket)
+            }
+            Err(err) => {
+                error!(path = %path.display(), err = %err, "failed to create unix socket");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Every connection holds a sender, so the receiver completes once all of them are closed
+    let (connections, mut closed) = mpsc::channel::<()>(1);
+
+    for listener in listeners {
+        let connections = connections.clone();
+        tokio::spawn(accept_loop(
+            listener,
+            state.clone(),
+            connections,
+            "client",
+            serve_resp,
+        ));
+    }
+    for listener in memcache_listeners {
+        let connections = connections.clone();
+        let name = "memcached client";
+        tokio::spawn(accept_loop(
+            listener,
+            state.clone(),
+            connections,
+            name,
+            serve_memcache,
+        ));
+    }
+    if let Some((acceptor, listeners)) = tls_listeners {
+        for listener in listeners {
+            let connections = connections.clone();
+            let acceptor = acceptor.clone();
+            let serve =
+                move |stream, state, client| serve_tls(acceptor.clone(), stream, state, client);
+            tokio::spawn(accept_loop(
+                listener,
+                state.clone(),
+                connections,
+                "tls client",
+                serve,
+            ));
+        }
+    }
+    if let Some(socket) = unix_socket {
+        let connections = connections.clone();
+        tokio::spawn(accept_loop(
+            socket,
+            state.clone(),
+            connections,
+            "client",
+            serve_resp,
+        ));
+    }
+    drop(connections);
+
+    let _ = state.shutdown.subscribe().wait_for(Option::is_some).await;
+    info!("shutting down, waiting for clients to finish");
+    let timeout = Duration::from_secs(state.config.read().unwrap().shutdown_timeout);
+    if tokio::time::timeout(timeout, closed.recv()).await.is_err() {
+        warn!("clients still connected after shutdown timeout, closing anyway");
+    }
+
+    let mode = state.shutdown.borrow().unwrap_or(ShutdownMode::Save);
+    if mode == ShutdownMode::Save {
+        if let Some(Err(err)) = state.aof.as_ref().map(|aof| aof.lock().unwrap().sync()) {
+            error!(err = %err, "failed to sync append only file");
+        }
+        if let Err(err) = state.bitcask.lock().unwrap().sync() {
+            error!(err = %err, "failed to sync data files");
+        }
+    }
+    if let Err(err) = state.bitcask.lock().unwrap().close() {
+        error!(err = %err, "failed to release data directory lock");
+    }
+    info!("knowsql server stopped");
+    ExitCode::SUCCESS
+}
+
+/// Load the configuration again and apply its runtime keys, the read-only keys keep their
+/// running value
+fn reload_config(state: &State, args: &config::Args) {
+    let loaded = match config::load(args).and_then(|config| config.validate().map(|_| config)) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            error!(
+                err = err,
+                "invalid configuration, keeping the running configuration"
+            );
             return;
         }
     };
 
-    for stream in listener.incoming() {
-        let stream = match stream {
-            Ok(stream) => stream,
+    let reloaded = state.reconfigure(|config| {
+        let (reloaded, ignored) = config.reload(&loaded);
+        for key in ignored {
+            warn!(
+                key = key,
+                "changing this key requires a restart, keeping its running value"
+            );
+        }
+        Ok(reloaded)
+    });
+    match reloaded {
+        Ok(()) => info!("configuration reloaded"),
+        Err(err) => error!(err = err, "failed to reload configuration"),
+    }
+}
+
+/// Listen on `port` of every address, logging the address that could not be bound
+async fn bind_all(addrs: &[IpAddr], port: u16) -> Option<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let addr = SocketAddr::new(*addr, port);
+        match TcpListener::bind(addr).await {
+            Ok(listener) => listeners.push(listener),
             Err(err) => {
-                error!(err = %err, "failed to accept client, continuing to serve next client");
-                continue;
+                error!(addr = %addr, err = %err, "failed to create TcpListener");
+                return None;
             }
-        };
+        }
+        info!(addr = %addr, "listening");
+    }
+    Some(listeners)
+}
+
+/// Serve a client of any kind of listener, taking the shared state by value for the spawned task
+async fn serve_resp<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    state: Arc<State>,
+    client: Option<Arc<Client>>,
+) {
+    resp::handle_client(stream, &state, client).await
+}
+
+async fn serve_memcache<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    state: Arc<State>,
+    client: Option<Arc<Client>>,
+) {
+    memcache::handle_client(stream, &state, client).await
+}
 
-        let bitcask = bitcask.clone();
-        std::thread::spawn(move || handle_client(stream, bitcask));
+/// The TLS port and its acceptor, when a TLS port is configured
+fn tls_port_acceptor(config: &Config) -> io::Result<Option<(u16, TlsAcceptor)>> {
+    let Some(port) = config.tls_port else {
+        return Ok(None);
+    };
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tls_port requires tls_cert_file and tls_key_file",
+        ));
+    };
+
+    let acceptor = tls::acceptor(
+        cert_file,
+        key_file,
+        config.tls_ca_cert_file.as_deref(),
+        config.tls_auth_clients,
+    )?;
+    Ok(Some((port, acceptor)))
+}
+
+/// Complete the TLS handshake on the client's own task, so a slow client does not hold up others
+async fn serve_tls(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    state: Arc<State>,
+    client: Option<Arc<Client>>,
+) {
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => resp::handle_client(stream, &state, client).await,
+        Ok(Err(err)) => info!(err = %err, "TLS handshake failed"),
+        Err(_) => info!("TLS handshake timed out"),
     }
 }
 
-fn handle_client(mut stream: TcpStream, bitcask: Arc<Mutex<BitCask>>) {
-    let _guard = span!(
-        Level::INFO,
-        "client",
-        addr = stream
-            .peer_addr()
-            .expect("every client must have a peer_addr")
-            .to_string(),
-    )
-    .entered();
-
-    let mut buffer = [0; 1024 * 1024];
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
-
-    info!("new connection");
+/// Accept clients until shutdown, serving each on its own task while it is in the registry.
+/// Clients beyond `maxclients` are served without a registration, to be refused
+async fn accept_loop<L: Listener, F, Fut>(
+    listener: L,
+    state: Arc<State>,
+    connections: mpsc::Sender<()>,
+    name: &'static str,
+    serve: F,
+) where
+    F: Fn(L::Stream, Arc<State>, Option<Arc<Client>>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut shutdown = state.shutdown.subscribe();
+
     loop {
-        trace!("reading from stream");
-        let read = match stream.read(&mut buffer) {
+        let accepted = tokio::select! {
+            accepted = listener.accept_client() => accepted,
+            _ = shutdown.wait_for(Option::is_some) => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
             Err(err) => {
-                debug!(err = %err, "failed to read from stream");
-                break;
-            }
-            Ok(read) if read == 0 => {
-                debug!("client closed connection");
-                break;
+                error!(err = %err, "failed to accept client, continuing to serve next client");
+                // Accepting fails when out of file descriptors, back off rather than spin
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
             }
-            Ok(read) => read,
         };
 
-        let mut consumed = 0;
-        while consumed <= read {
-            let (remaining, command) = match parse_command(&buffer[consumed..read]) {
-                Ok(c) => c,
-                Err(_) => {
-                    break;
-                }
-            };
-
-            let size = (read - consumed) - remaining.len();
-            consumed += size;
-            debug!(command = ?command, size = size, "handling command");
-
-            match command {
-                Command::Command(SubCommand::Docs) => {
-                    let response = Data::Array(
-                        Command::all_commands()
-                            .iter()
-                            .flat_map(|(name, doc)| {
-                                vec![
-                                    Data::BulkString(name),
-                                    Data::Array(doc.iter().map(|d| Data::BulkString(d)).collect()),
-                                ]
-                            })
-                            .collect(),
-                    );
-
-                    let resp = response.as_str().expect("constructed from static values");
-                    writer.write_all(resp.as_bytes()).unwrap();
-                }
-                Command::Echo(message) => {
-                    writer
-                        .write_all(format!("+{}\r\n", message).as_bytes())
-                        .unwrap();
-                }
-                Command::Get(key) => {
-                    let mut bitcask = bitcask.lock().unwrap();
-                    match bitcask.get(key) {
-                        Some(value) => writer
-                            .write_all(format!("+{}\r\n", value).as_bytes())
-                            .unwrap(),
-                        None => writer.write_all(b"$-1\r\n").unwrap(),
-                    }
-                }
-                Command::Keys(None) => {
-                    let keys = bitcask.lock().unwrap().keys();
-                    let response =
-                        Data::Array(keys.iter().map(|key| Data::BulkString(key)).collect());
-
-                    match response.as_str() {
-                        Some(resp) => writer.write_all(resp.as_bytes()).unwrap(),
-                        None => {
-                            writer.write_all(b"-failed to list keys\r\n").unwrap();
-                        }
-                    }
-                }
-                Command::Keys(Some(pattern)) => match Regex::new(pattern) {
-                    Ok(re) => {
-                        let keys = bitcask.lock().unwrap().keys();
-                        let response = Data::Array(
-                            keys.iter()
-                                .filter(|key| re.is_match(key))
-                                .map(|key| Data::BulkString(key))
-                                .collect(),
-                        );
-
-                        let resp = response.as_str().expect("constructed from static values");
-                        writer.write_all(resp.as_bytes()).unwrap();
-                    }
-                    Err(_) => {
-                        trace!(pattern = pattern, "invalid regex pattern");
-                        writer.write_all(b"-invalid regex pattern\r\n").unwrap();
-                        writer.flush().unwrap();
-                    }
-                },
-                Command::Set(key, value) => match bitcask.lock().unwrap().put(key, value) {
-                    Ok(_) => {
-                        writer.write_all(b"+OK\r\n").unwrap();
-                    }
-                    Err(_) => {
-                        writer
-                            .write_all("-failed to set key value pair\r\n".as_bytes())
-                            .unwrap();
-                    }
-                },
-                Command::DbSize => {
-                    let size = bitcask.lock().unwrap().keys().len();
-
-                    writer
-                        .write_all(format!(":{}\r\n", size).as_bytes())
-                        .unwrap();
-                }
-                Command::Ping => {
-                    writer.write_all(b"+PONG\r\n").unwrap();
-                }
-                Command::Quit => {
-                    debug!("client quitting");
-                    writer.write_all(b"+OK\r\n").unwrap();
-                    break;
+        let span = span!(Level::INFO, "client", kind = name, addr = addr);
+        let maxclients = state.config.read().unwrap().maxclients;
+        let client = state.clients.register(addr, maxclients);
+        let id = client.as_ref().map(|client| client.id);
+        let serving = serve(stream, state.clone(), client);
+        let (state, connection) = (state.clone(), connections.clone());
+        tokio::spawn(
+            async move {
+                serving.await;
+                if let Some(id) = id {
+                    state.clients.remove(id);
                 }
+                drop(connection);
             }
+            .instrument(span),
+        );
+    }
+}
+
+/// Merge the store when the stats show enough reclaimable space. The store is only locked while
+/// a single data file is merged, so clients are not held up for the whole merge
+fn merge_if_due(bitcask: &Mutex<BitCask>, dead_ratio: f64, min_dead_bytes: u64) {
+    let mut merge = {
+        let mut bitcask = bitcask.lock().unwrap();
+        let stats = bitcask.stats();
 
-            trace!("flushing writer");
-            writer.flush().unwrap();
+        if stats.dead_bytes() < min_dead_bytes || stats.dead_ratio() < dead_ratio {
+            trace!(dead_bytes = stats.dead_bytes(), "merge not due");
+            return;
+        }
+
+        info!(
+            dead_bytes = stats.dead_bytes(),
+            total_bytes = stats.total_bytes(),
+            "merging data files"
+        );
+        match bitcask.start_merge() {
+            Ok(merge) => merge,
+            Err(err) => {
+                error!(err = %err, "failed to start merging data files");
+                return;
+            }
         }
+    };
 
-        buffer.copy_within(consumed..read, 0);
+    loop {
+        match bitcask.lock().unwrap().merge_segment(&mut merge) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => {
+                error!(err = %err, "failed to merge data files");
+                if let Err(err) = bitcask.lock().unwrap().abort_merge(merge) {
+                    error!(err = %err, "failed to abort merging data files");
+                }
+                return;
+            }
+        }
     }
 
-    debug!("client going away");
+    let mut bitcask = bitcask.lock().unwrap();
+    match bitcask.finish_merge(merge) {
+        Ok(_) => info!(
+            total_bytes = bitcask.stats().total_bytes(),
+            "merge complete"
+        ),
+        Err(err) => error!(err = %err, "failed to merge data files"),
+    }
 }
@@ -0,0 +1,440 @@
+//! Memcached text protocol front-end, mapping requests onto the same storage operations as RESP
+
+use crate::{
+    acl::DEFAULT_USER,
+    clients::Client,
+    resp::{output_limit_reached, Connection, Event, Flow},
+    State,
+};
+use knowsql_bitcask::BitCask;
+use knowsql_parser::{
+    command::Command,
+    memcache::{parse_request, Request, RequestError, StoreMode},
+};
+
+use std::{io::Write, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error, info};
+
+/// Store a value and log it like a SET, replying with the memcached response line
+fn store(bitcask: &mut BitCask, state: &State, key: &str, value: &[u8]) -> &'static str {
+    // Values are read back as strings, so binary values can not be stored yet
+    if std::str::from_utf8(value).is_err() {
+        return "SERVER_ERROR value is not valid utf8";
+    }
+    if let Err(err) = log(state, &Command::Set(key.into(), value.into())) {
+        return err;
+    }
+    if let Err(err) = bitcask.put(key, value) {
+        error!(err = %err, "failed to store value");
+        return "SERVER_ERROR failed to store value";
+    }
+    "STORED"
+}
+
+/// Log a command before it is applied, like RESP does
+fn log(state: &State, command: &Command) -> Result<(), &'static str> {
+    state.log(command).map_err(|err| {
+        error!(err = %err, "failed to append to append only file");
+        "SERVER_ERROR failed to write to append only file"
+    })
+}
+
+/// Name of the request as memcached clients send it, recorded in the client registry
+fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::Get { cas: false, .. } => "get",
+        Request::Get { cas: true, .. } => "gets",
+        Request::Store { mode, .. } => match mode {
+            StoreMode::Set => "set",
+            StoreMode::Add => "add",
+            StoreMode::Replace => "replace",
+        },
+        Request::Delete { .. } => "delete",
+        Request::Incr { delta, .. } if *delta < 0 => "decr",
+        Request::Incr { .. } => "incr",
+        Request::Touch { .. } => "touch",
+        Request::FlushAll { .. } => "flush_all",
+        Request::Version => "version",
+        Request::Stats => "stats",
+        Request::Quit => "quit",
+    }
+}
+
+/// Handle a request, writing its response unless the client asked for none
+fn handle_request(
+    writer: &mut impl Write,
+    request: &Request,
+    state: &State,
+) -> std::io::Result<()> {
+    let mut bitcask = state.bitcask.lock().unwrap();
+
+    let (response, noreply) = match request {
+        Request::Get { keys, cas } => {
+            for key in keys {
+                let value = match bitcask.get(key) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!(err = %err, "failed to read value");
+                        return write!(writer, "SERVER_ERROR {}\r\n", err);
+                    }
+                };
+                // Stores with flags are refused and there is no compare and swap, so both are 0
+                match cas {
+                    true => write!(writer, "VALUE {} 0 {} 0\r\n", key, value.len())?,
+                    false => write!(writer, "VALUE {} 0 {}\r\n", key, value.len())?,
+                }
+                writer.write_all(value.as_bytes())?;
+                writer.write_all(b"\r\n")?;
+            }
+            ("END".to_string(), false)
+        }
+        Request::Store {
+            mode,
+            key,
+            value,
+            noreply,
+            flags,
+            exptime,
+        } => {
+            let exists = bitcask.contains_key(key);
+            let response = match mode {
+                // Neither would be returned by get, so refuse them rather than drop them
+                _ if *flags != 0 => "CLIENT_ERROR flags are not supported",
+                _ if *exptime != 0 => "CLIENT_ERROR expiry times are not supported",
+                StoreMode::Add if exists => "NOT_STORED",
+                StoreMode::Replace if !exists => "NOT_STORED",
+                _ => store(&mut bitcask, state, key, value),
+            };
+            (response.to_string(), *noreply)
+        }
+        Request::Delete { key, noreply } => {
+            let response = if !bitcask.contains_key(key) {
+                "NOT_FOUND"
+            } else if let Err(err) = log(state, &Command::Del(vec![(*key).into()])) {
+                err
+            } else if let Err(err) = bitcask.delete(key) {
+                error!(err = %err, "failed to delete value");
+                "SERVER_ERROR failed to delete value"
+            } else {
+                "DELETED"
+            };
+            (response.to_string(), *noreply)
+        }
+        Request::Incr {
+            key,
+            delta,
+            noreply,
+        } => {
+            let response = match bitcask
+                .get(key)
+                .map(|value| value.map(|value| value.parse::<u64>()))
+            {
+                Err(err) => {
+                    error!(err = %err, "failed to read value");
+                    format!("SERVER_ERROR {}", err)
+                }
+                Ok(None) => "NOT_FOUND".to_string(),
+                Ok(Some(Err(_))) => {
+                    "CLIENT_ERROR cannot increment or decrement non-numeric value".to_string()
+                }
+                Ok(Some(Ok(value))) => {
+                    // Like memcached, incr wraps around and decr stops at 0
+                    let value = match u64::try_from(*delta) {
+                        Ok(delta) => value.wrapping_add(delta),
+                        Err(_) => value.saturating_sub(delta.unsigned_abs() as u64),
+                    }
+                    .to_string();
+                    match store(&mut bitcask, state, key, value.as_bytes()) {
+                        "STORED" => value,
+                        err => err.to_string(),
+                    }
+                }
+            };
+            (response, *noreply)
+        }
+        // Keys never expire, touch only reports whether the key exists
+        Request::Touch { key, noreply, .. } => match bitcask.contains_key(key) {
+            true => ("TOUCHED".to_string(), *noreply),
+            false => ("NOT_FOUND".to_string(), *noreply),
+        },
+        Request::FlushAll { noreply } => {
+            let response = match log(state, &Command::FlushAll) {
+                Ok(()) => match bitcask.clear() {
+                    Ok(()) => "OK",
+                    Err(err) => {
+                        error!(err = %err, "failed to flush values");
+                        "SERVER_ERROR failed to flush values"
+                    }
+                },
+                Err(err) => err,
+            };
+            (response.to_string(), *noreply)
+        }
+        Request::Version => (format!("VERSION {}", env!("CARGO_PKG_VERSION")), false),
+        Request::Stats => {
+            let stats = bitcask.stats();
+            write!(writer, "STAT pid {}\r\n", std::process::id())?;
+            write!(
+                writer,
+                "STAT uptime {}\r\n",
+                state.started.elapsed().as_secs()
+            )?;
+            write!(writer, "STAT time {}\r\n", chrono::Utc::now().timestamp())?;
+            write!(writer, "STAT version {}\r\n", env!("CARGO_PKG_VERSION"))?;
+            write!(writer, "STAT curr_items {}\r\n", stats.keys)?;
+            write!(writer, "STAT bytes {}\r\n", stats.live_bytes())?;
+            ("END".to_string(), false)
+        }
+        Request::Quit => return Ok(()),
+    };
+
+    if !noreply {
+        write!(writer, "{}\r\n", response)?;
+    }
+    Ok(())
+}
+
+/// Handle every request in `buffer`, returning how many bytes were consumed and whether the
+/// client is done
+fn handle_buffer(
+    buffer: &[u8],
+    state: &State,
+    client: &Client,
+    out: &mut Vec<u8>,
+) -> (usize, Flow) {
+    let mut remaining = buffer;
+    let (limits, hard_limit) = {
+        let config = state.config.read().unwrap();
+        (config.limits(), config.client_output_buffer_hard_limit)
+    };
+
+    loop {
+        let parsed = parse_request(remaining, &limits);
+        if let Ok((_, request)) = &parsed {
+            client.record(request_name(request));
+        }
+
+        let (rest, flow) = match parsed {
+            Ok((rest, Request::Quit)) => {
+                debug!("client quitting");
+                (rest, Flow::Close)
+            }
+            Ok((rest, request)) => {
+                debug!(request = ?request, "handling request");
+                handle_request(out, &request, state).expect("writing to a vec can not fail");
+                (rest, Flow::Continue)
+            }
+            Err(RequestError::Incomplete) => break,
+            Err(RequestError::Unknown { remaining }) => {
+                out.extend_from_slice(b"ERROR\r\n");
+                (remaining, Flow::Continue)
+            }
+            Err(RequestError::Client { remaining, message }) => {
+                debug!(err = message, "invalid request");
+                write!(out, "CLIENT_ERROR {}\r\n", message).unwrap();
+                (remaining, Flow::Continue)
+            }
+            Err(RequestError::TooLarge) => {
+                debug!("request exceeds protocol limits, closing connection");
+                out.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n");
+                (remaining, Flow::Close)
+            }
+        };
+
+        remaining = rest;
+        if flow == Flow::Close || output_limit_reached(out, hard_limit) {
+            return (buffer.len() - remaining.len(), Flow::Close);
+        }
+    }
+
+    (buffer.len() - remaining.len(), Flow::Continue)
+}
+
+/// Serve a memcached client until it disconnects
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    state: &Arc<State>,
+    client: Option<Arc<Client>>,
+) {
+    info!("new connection");
+    // The text protocol has no way to authenticate, so it is only served when RESP needs no AUTH
+    let unrestricted = state
+        .acl
+        .read()
+        .unwrap()
+        .user(DEFAULT_USER)
+        .is_some_and(|user| user.is_unrestricted());
+    if !unrestricted {
+        debug!("default user is restricted, refusing connection");
+        let _ = stream
+            .write_all(b"SERVER_ERROR authentication required\r\n")
+            .await;
+        let _ = stream.shutdown().await;
+        return;
+    }
+    // Clients beyond maxclients are not registered
+    let Some(client) = client else {
+        debug!("max number of clients reached, refusing connection");
+        let _ = stream
+            .write_all(b"SERVER_ERROR max number of clients reached\r\n")
+            .await;
+        let _ = stream.shutdown().await;
+        return;
+    };
+
+    let mut connection = Connection::new(stream);
+
+    loop {
+        match connection.next_event(state, &client).await {
+            Event::Read(Err(err)) => {
+                debug!(err = %err, "failed to read from stream");
+                break;
+            }
+            Event::Read(Ok(0)) => {
+                debug!("client closed connection");
+                break;
+            }
+            Event::Read(Ok(read)) => {
+                // Commands block on the store and its file I/O, so they are kept off the async
+                // workers
+                let (buffer, mut out) = connection.take();
+                let (task_state, task_client) = (Arc::clone(state), Arc::clone(&client));
+                let handled = tokio::task::spawn_blocking(move || {
+                    let (consumed, flow) =
+                        handle_buffer(&buffer, &task_state, &task_client, &mut out);
+                    (buffer, out, consumed, flow)
+                })
+                .await;
+                let (mut buffer, mut out, consumed, mut flow);
+                match handled {
+                    Ok(handled) => (buffer, out, consumed, flow) = handled,
+                    Err(err) => {
+                        error!(err = %err, "failed to handle commands");
+                        break;
+                    }
+                }
+                buffer.drain(..consumed);
+                client.record_input(read, buffer.len());
+
+                let query_buffer_limit = state.config.read().unwrap().client_query_buffer_limit;
+                if flow == Flow::Continue && buffer.len() > query_buffer_limit {
+                    debug!(
+                        bytes = buffer.len(),
+                        "query buffer limit reached, closing connection"
+                    );
+                    out.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n");
+                    flow = Flow::Close;
+                }
+
+                connection.restore(buffer, out, state, &client);
+                connection.closing |= flow == Flow::Close;
+            }
+            Event::Written(Err(err)) => {
+                debug!(err = %err, "failed to write to stream");
+                break;
+            }
+            Event::Written(Ok(_)) => (),
+            Event::Shutdown => {
+                debug!("server shutting down, closing connection");
+                connection.closing = true;
+            }
+            Event::Killed => {
+                debug!("client killed, closing connection");
+                connection.closing = true;
+            }
+            Event::Idle => {
+                debug!("client idle for too long, closing connection");
+                break;
+            }
+            Event::Stalled => {
+                debug!(
+                    bytes = connection.waiting().len(),
+                    "client not reading its replies, closing connection"
+                );
+                break;
+            }
+        }
+
+        if connection.closing && connection.waiting().is_empty() {
+            connection.shutdown().await;
+            break;
+        }
+    }
+
+    debug!("client going away");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use knowsql_parser::parse_command;
+    use std::path::Path;
+
+    #[test]
+    fn test_deletes_are_logged() {
+        let config = Config {
+            appendonly: true,
+            ..Config::default()
+        };
+        let state = State::for_test("memcache-aof", config);
+        let data_dir = state.config.read().unwrap().data_dir.clone();
+        let limits = state.config.read().unwrap().limits();
+
+        let requests = [
+            "set a 1 0 1\r\n1\r\n",
+            "set a 0 60 1\r\n1\r\n",
+            "set a 0 0 1\r\n1\r\n",
+            "set b 0 0 1\r\n2\r\n",
+            "delete a\r\n",
+            "delete missing\r\n",
+            "flush_all\r\n",
+            "set c 0 0 1\r\n3\r\n",
+        ];
+        let mut out = Vec::new();
+        for request in requests {
+            let (_, request) = parse_request(request.as_bytes(), &limits).unwrap();
+            handle_request(&mut out, &request, &state).unwrap();
+        }
+        assert_eq!(
+            out,
+            b"CLIENT_ERROR flags are not supported\r\n\
+              CLIENT_ERROR expiry times are not supported\r\n\
+              STORED\r\nSTORED\r\nDELETED\r\nNOT_FOUND\r\nOK\r\nSTORED\r\n"
+        );
+
+        // Replay the log into a fresh store, deleted keys must stay gone
+        let log = std::fs::read(Path::new(&data_dir).join("appendonly.aof")).unwrap();
+        let replayed = format!("{}-replayed", data_dir);
+        let _ = std::fs::remove_dir_all(&replayed);
+        let mut cask = BitCask::open(replayed.clone().into()).unwrap();
+        let mut remaining = &log[..];
+        while !remaining.is_empty() {
+            let (rest, command) = parse_command(remaining).unwrap();
+            match command {
+                Command::Set(key, value) => cask.put(&key, &value).unwrap(),
+                Command::Del(keys) => keys.iter().for_each(|key| {
+                    cask.delete(key).unwrap();
+                }),
+                Command::FlushAll => cask.clear().unwrap(),
+                command => panic!("unexpected command {:?}", command),
+            }
+            remaining = rest;
+        }
+        assert_eq!(cask.keys(), vec!["c".to_string()]);
+        drop(cask);
+
+        // Deleted keys stay gone once either store is reopened
+        let cask = BitCask::open(replayed.clone().into()).unwrap();
+        assert_eq!(cask.keys(), vec!["c".to_string()]);
+        state.bitcask.lock().unwrap().close().unwrap();
+        let cask = BitCask::open(data_dir.clone().into()).unwrap();
+        assert_eq!(cask.keys(), vec!["c".to_string()]);
+        drop(cask);
+
+        std::fs::remove_dir_all(data_dir).unwrap();
+        std::fs::remove_dir_all(replayed).unwrap();
+    }
+}
@@ -0,0 +1,920 @@
+//! RESP connections, the protocol spoken by redis clients
+
+use crate::{
+    acl::{Denied, DEFAULT_USER},
+    clients::Client,
+    State,
+};
+use knowsql_parser::{
+    command::{
+        AclCommand, ClientCommand, Command, ConfigCommand, Hello, KillFilter, ShutdownMode,
+        SubCommand,
+    },
+    parse_command_with,
+    protocol::{encode, resp3::Data, Protocol},
+    ParseError,
+};
+use regex::Regex;
+
+use std::{io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, info, trace};
+
+/// Bytes reserved for each read, the buffer grows beyond this for larger requests
+pub const READ_SIZE: usize = 4 * 1024;
+
+/// Whether to keep serving a connection after a command
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    Close,
+}
+
+/// State of a single connection
+#[derive(Debug)]
+pub struct Session {
+    pub protocol: Protocol,
+    /// User the connection is authenticated as, `None` until AUTH succeeds
+    pub user: Option<String>,
+    /// The connection in the client registry
+    pub client: Arc<Client>,
+}
+
+impl Session {
+    /// Connections start as the default user when it needs no password
+    pub fn new(state: &State, client: Arc<Client>) -> Session {
+        let acl = state.acl.read().unwrap();
+        let user = acl
+            .user(DEFAULT_USER)
+            .filter(|user| user.is_enabled() && user.is_nopass())
+            .map(|_| DEFAULT_USER.to_string());
+        client.set_user(user.as_deref());
+
+        Session {
+            protocol: Protocol::default(),
+            user,
+            client,
+        }
+    }
+}
+
+/// Write a reply encoded for the protocol the client negotiated
+fn reply(out: &mut Vec<u8>, protocol: Protocol, data: &Data) {
+    match protocol {
+        Protocol::Resp2 => data.write_resp2_to(out),
+        Protocol::Resp3 => data.write_to(out),
+    }
+    .expect("writing to a vec can not fail");
+}
+
+/// Stream an array of bulk strings without building the reply in memory first
+fn reply_bulk_array<'a>(out: &mut Vec<u8>, items: impl ExactSizeIterator<Item = &'a str>) {
+    encode::write_array_header(out, items.len()).unwrap();
+    for item in items {
+        encode::write_bulk(out, item.as_bytes()).unwrap();
+    }
+}
+
+/// Log a command before it is applied, replying with an error when that fails. Returns whether
+/// the command may be applied
+fn log_command(command: &Command, state: &State, protocol: Protocol, out: &mut Vec<u8>) -> bool {
+    match state.log(command) {
+        Ok(()) => true,
+        Err(err) => {
+            error!(err = %err, "failed to append to append only file");
+            reply(
+                out,
+                protocol,
+                &Data::Error("ERR failed to write to append only file"),
+            );
+            false
+        }
+    }
+}
+
+/// Execute a command, writing its reply to `out`
+pub fn handle_command(
+    command: &Command,
+    session: &mut Session,
+    state: &State,
+    out: &mut Vec<u8>,
+) -> Flow {
+    let protocol = session.protocol;
+
+    match command {
+        Command::Acl(AclCommand::List) => {
+            let users = state.acl.read().unwrap().list();
+            reply_bulk_array(out, users.iter().map(String::as_str));
+        }
+        Command::Acl(AclCommand::SetUser(name, rules)) => {
+            match state.acl.write().unwrap().set_user(name, rules) {
+                Ok(()) => reply(out, protocol, &Data::String("OK")),
+                Err(err) => reply(out, protocol, &Data::Error(&format!("ERR {}", err))),
+            }
+        }
+        Command::Acl(AclCommand::WhoAmI) => {
+            let user = session.user.as_deref().unwrap_or(DEFAULT_USER);
+            reply(out, protocol, &Data::BulkString(user));
+        }
+        Command::Auth(name, password) => {
+            let default_nopass = state
+                .acl
+                .read()
+                .unwrap()
+                .user(DEFAULT_USER)
+                .is_some_and(|user| user.is_nopass());
+            let user = name.as_deref().unwrap_or(DEFAULT_USER);
+
+            if name.is_none() && default_nopass {
+                reply(out, protocol, &Data::Error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"));
+            } else if authenticate(session, state, user, password) {
+                reply(out, protocol, &Data::String("OK"));
+            } else {
+                reply(out, protocol, &Data::Error(WRONGPASS));
+            }
+        }
+        Command::Client(ClientCommand::Id) => {
+            reply(out, protocol, &Data::Integer(session.client.id as i64))
+        }
+        Command::Client(ClientCommand::Info) => {
+            let info = format!("{}\n", session.client.describe());
+            reply(out, protocol, &Data::VerbatimString("txt", &info));
+        }
+        Command::Client(ClientCommand::List) => {
+            let clients = state.clients.list();
+            let list: String = clients
+                .iter()
+                .map(|client| format!("{}\n", client.describe()))
+                .collect();
+            reply(out, protocol, &Data::VerbatimString("txt", &list));
+        }
+        Command::Client(ClientCommand::GetName) => match session.client.name() {
+            Some(name) => reply(out, protocol, &Data::BulkString(&name)),
+            None => reply(out, protocol, &Data::Null),
+        },
+        Command::Client(ClientCommand::SetName(name)) if !valid_client_name(name) => {
+            reply(out, protocol, &Data::Error(INVALID_CLIENT_NAME))
+        }
+        Command::Client(ClientCommand::SetName(name)) => {
+            session
+                .client
+                .set_name(Some(name.as_ref()).filter(|name| !name.is_empty()));
+            reply(out, protocol, &Data::String("OK"));
+        }
+        Command::Client(ClientCommand::KillAddr(addr)) => {
+            let filter = KillFilter {
+                addr: Some(addr.clone()),
+                ..KillFilter::default()
+            };
+            match state.clients.kill(&filter, session.client.id) {
+                0 => reply(out, protocol, &Data::Error("ERR No such client")),
+                _ => reply(out, protocol, &Data::String("OK")),
+            }
+        }
+        Command::Client(ClientCommand::Kill(filter)) => {
+            let killed = state.clients.kill(filter, session.client.id);
+            info!(killed = killed, "clients killed by client");
+            reply(out, protocol, &Data::Integer(killed as i64));
+        }
+        Command::Command(SubCommand::Docs) => {
+            let response = Data::Map(
+                Command::all_commands()
+                    .iter()
+                    .map(|spec| {
+                        (
+                            Data::BulkString(spec.name),
+                            Data::Array(spec.docs.iter().map(|d| Data::BulkString(d)).collect()),
+                        )
+                    })
+                    .collect(),
+            );
+
+            reply(out, protocol, &response);
+        }
+        Command::Config(ConfigCommand::Get(patterns)) => {
+            let values = state.config.read().unwrap().get(patterns);
+            let response = Data::Map(
+                values
+                    .iter()
+                    .map(|(key, value)| (Data::BulkString(key), Data::BulkString(value)))
+                    .collect(),
+            );
+            reply(out, protocol, &response);
+        }
+        Command::Config(ConfigCommand::Set(pairs)) => {
+            match state.reconfigure(|config| config.set(pairs)) {
+                Ok(()) => {
+                    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_ref()).collect();
+                    info!(keys = ?keys, "configuration changed by client");
+                    reply(out, protocol, &Data::String("OK"));
+                }
+                Err(err) => reply(out, protocol, &Data::Error(&format!("ERR {}", err))),
+            }
+        }
+        Command::Config(ConfigCommand::Rewrite) => {
+            let rewritten = {
+                let mut config = state.config.write().unwrap();
+                // Users changed with ACL SETUSER are written as well
+                config.users = state.acl.read().unwrap().to_users();
+                config.rewrite(&state.config_path)
+            };
+            match rewritten {
+                Ok(()) => {
+                    info!(path = %state.config_path.display(), "configuration rewritten");
+                    reply(out, protocol, &Data::String("OK"));
+                }
+                Err(err) => reply(out, protocol, &Data::Error(&format!("ERR {}", err))),
+            }
+        }
+        Command::Echo(message) => reply(out, protocol, &Data::BulkString(message)),
+        Command::Get(key) => {
+            let value = state.bitcask.lock().unwrap().get(key);
+            match value {
+                Ok(Some(value)) => reply(out, protocol, &Data::BulkString(&value)),
+                Ok(None) => reply(out, protocol, &Data::Null),
+                Err(err) => reply(out, protocol, &Data::Error(&format!("ERR {}", err))),
+            }
+        }
+        Command::Hello(Hello {
+            version,
+            auth,
+            name,
+        }) => match version.as_deref().map(str::parse::<u8>) {
+            Some(Err(_)) => reply(
+                out,
+                protocol,
+                &Data::Error("ERR Protocol version is not an integer or out of range"),
+            ),
+            Some(Ok(version)) if !(2..=3).contains(&version) => reply(
+                out,
+                protocol,
+                &Data::Error("NOPROTO unsupported protocol version"),
+            ),
+            _ if name.as_deref().is_some_and(|name| !valid_client_name(name)) => {
+                reply(out, protocol, &Data::Error(INVALID_CLIENT_NAME))
+            }
+            // Nothing changes unless the credentials are valid
+            _ if auth
+                .as_ref()
+                .is_some_and(|(user, password)| !authenticate(session, state, user, password)) =>
+            {
+                reply(out, protocol, &Data::Error(WRONGPASS))
+            }
+            version => {
+                if let Some(name) = name {
+                    session
+                        .client
+                        .set_name(Some(name.as_ref()).filter(|name| !name.is_empty()));
+                }
+                match version {
+                    Some(Ok(2)) => session.protocol = Protocol::Resp2,
+                    Some(Ok(3)) => session.protocol = Protocol::Resp3,
+                    _ => (),
+                }
+                debug!(protocol = ?session.protocol, "hello");
+
+                let response = Data::Map(vec![
+                    (Data::BulkString("server"), Data::BulkString("knowsql")),
+                    (
+                        Data::BulkString("version"),
+                        Data::BulkString(env!("CARGO_PKG_VERSION")),
+                    ),
+                    (
+                        Data::BulkString("proto"),
+                        Data::Integer(match session.protocol {
+                            Protocol::Resp2 => 2,
+                            Protocol::Resp3 => 3,
+                        }),
+                    ),
+                    (Data::BulkString("mode"), Data::BulkString("standalone")),
+                    (Data::BulkString("role"), Data::BulkString("master")),
+                    (Data::BulkString("modules"), Data::Array(vec![])),
+                ]);
+                reply(out, session.protocol, &response);
+            }
+        },
+        Command::Info(section) => {
+            let info = crate::info::info(&state.bitcask.lock().unwrap(), section.as_deref());
+            reply(out, protocol, &Data::VerbatimString("txt", &info));
+        }
+        Command::Keys(None) => {
+            let bitcask = state.bitcask.lock().unwrap();
+            reply_bulk_array(out, bitcask.iter_keys());
+        }
+        Command::Keys(Some(pattern)) => match Regex::new(pattern) {
+            Ok(re) => {
+                let bitcask = state.bitcask.lock().unwrap();
+                let keys: Vec<&str> = bitcask.iter_keys().filter(|key| re.is_match(key)).collect();
+                reply_bulk_array(out, keys.into_iter());
+            }
+            Err(_) => {
+                trace!(pattern = %pattern, "invalid regex pattern");
+                reply(out, protocol, &Data::Error("ERR invalid regex pattern"));
+            }
+        },
+        // Values are read back as strings, so binary values can not be stored yet
+        Command::Set(_, value) if std::str::from_utf8(value).is_err() => {
+            reply(out, protocol, &Data::Error("ERR value is not valid utf8"))
+        }
+        Command::Set(key, value) => {
+            let mut bitcask = state.bitcask.lock().unwrap();
+            if !log_command(command, state, protocol, out) {
+                return Flow::Continue;
+            }
+
+            match bitcask.put(key, value) {
+                Ok(_) => reply(out, protocol, &Data::String("OK")),
+                Err(_) => reply(
+                    out,
+                    protocol,
+                    &Data::Error("ERR failed to set key value pair"),
+                ),
+            }
+        }
+        Command::Del(keys) => {
+            let mut bitcask = state.bitcask.lock().unwrap();
+            if !log_command(command, state, protocol, out) {
+                return Flow::Continue;
+            }
+
+            let deleted: std::io::Result<Vec<bool>> =
+                keys.iter().map(|key| bitcask.delete(key)).collect();
+            match deleted {
+                Ok(deleted) => {
+                    let count = deleted.into_iter().filter(|deleted| *deleted).count();
+                    reply(out, protocol, &Data::Integer(count as i64))
+                }
+                Err(_) => reply(out, protocol, &Data::Error("ERR failed to delete key")),
+            }
+        }
+        Command::FlushAll => {
+            let mut bitcask = state.bitcask.lock().unwrap();
+            if !log_command(command, state, protocol, out) {
+                return Flow::Continue;
+            }
+
+            match bitcask.clear() {
+                Ok(()) => reply(out, protocol, &Data::String("OK")),
+                Err(_) => reply(out, protocol, &Data::Error("ERR failed to flush keys")),
+            }
+        }
+        Command::DbSize => {
+            let size = state.bitcask.lock().unwrap().len();
+            reply(out, protocol, &Data::Integer(size as i64));
+        }
+        Command::Ping => reply(out, protocol, &Data::String("PONG")),
+        Command::Quit => {
+            debug!("client quitting");
+            reply(out, protocol, &Data::String("OK"));
+            return Flow::Close;
+        }
+        // Like Redis the connection is closed without a reply
+        Command::Shutdown(mode) => {
+            let mode = mode.unwrap_or(ShutdownMode::Save);
+            info!(mode = ?mode, "shutdown requested by client");
+            state.shutdown.send_replace(Some(mode));
+            return Flow::Close;
+        }
+    }
+
+    Flow::Continue
+}
+
+const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+const INVALID_CLIENT_NAME: &str =
+    "ERR Client names cannot contain spaces, newlines or special characters.";
+
+/// Authenticate the connection as `user`, returning whether the password is valid
+fn authenticate(session: &mut Session, state: &State, user: &str, password: &str) -> bool {
+    if !state.acl.read().unwrap().authenticate(user, password) {
+        debug!(user = user, "authentication failed");
+        return false;
+    }
+
+    debug!(user = user, "authenticated");
+    session.user = Some(user.to_string());
+    session.client.set_user(Some(user));
+    true
+}
+
+/// Names are shown in CLIENT LIST, so they may not break its format
+fn valid_client_name(name: &str) -> bool {
+    name.bytes().all(|c| c.is_ascii_graphic())
+}
+
+/// Check the user of the connection may run the command, returning the error reply when not
+fn check_access(command: &Command, session: &Session, state: &State) -> Result<(), String> {
+    // Connections may always negotiate, authenticate and leave
+    if matches!(
+        command,
+        Command::Auth(..) | Command::Hello(_) | Command::Quit
+    ) {
+        return Ok(());
+    }
+
+    let acl = state.acl.read().unwrap();
+    let Some((name, user)) = session
+        .user
+        .as_deref()
+        .and_then(|name| Some((name, acl.user(name)?)))
+        .filter(|(_, user)| user.is_enabled())
+    else {
+        return Err("NOAUTH Authentication required.".to_string());
+    };
+
+    match user.check(command) {
+        Ok(()) => Ok(()),
+        Err(Denied::Command) => {
+            let command_name = match command.subcommand() {
+                Some(subcommand) => format!("{}|{}", command.name(), subcommand),
+                None => command.name().to_string(),
+            };
+            Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                name,
+                command_name.to_ascii_lowercase()
+            ))
+        }
+        Err(Denied::Key) => Err("NOPERM No permissions to access a key".to_string()),
+    }
+}
+
+/// Whether the replies in `out` exceed the output buffer hard limit, dropping them when they do
+pub fn output_limit_reached(out: &mut Vec<u8>, hard_limit: usize) -> bool {
+    if hard_limit == 0 || out.len() <= hard_limit {
+        return false;
+    }
+
+    debug!(
+        bytes = out.len(),
+        "output buffer limit reached, closing connection"
+    );
+    out.clear();
+    true
+}
+
+/// A client connection with its input not handled yet and its replies not written yet. Replies
+/// are kept across batches of commands, so the output buffer limits apply to all of them
+pub struct Connection<S> {
+    reader: ReadHalf<S>,
+    writer: WriteHalf<S>,
+    buffer: Vec<u8>,
+    /// Replies, of which the first `written` bytes have been written
+    replies: Vec<u8>,
+    written: usize,
+    last_input: Instant,
+    /// When a write last made progress, or replies were queued while none were waiting
+    progress: Instant,
+    /// Since when the waiting replies are beyond the soft limit
+    over_soft_limit: Option<Instant>,
+    /// Once closing, no more input is read and the connection closes when its replies are written
+    pub closing: bool,
+}
+
+/// What happened next on a connection
+pub enum Event {
+    /// Bytes were read into the input, `0` when the client closed the connection
+    Read(io::Result<usize>),
+    Written(io::Result<usize>),
+    Shutdown,
+    Killed,
+    /// The client sent nothing for longer than `timeout`
+    Idle,
+    /// The client did not read its replies in time
+    Stalled,
+}
+
+impl<S: AsyncRead + AsyncWrite> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        let (reader, writer) = tokio::io::split(stream);
+        Connection {
+            reader,
+            writer,
+            buffer: Vec::new(),
+            replies: Vec::new(),
+            written: 0,
+            last_input: Instant::now(),
+            progress: Instant::now(),
+            over_soft_limit: None,
+            closing: false,
+        }
+    }
+
+    /// Replies not written yet
+    pub fn waiting(&self) -> &[u8] {
+        &self.replies[self.written..]
+    }
+
+    /// Take the input and the waiting replies, to handle commands away from the connection
+    pub fn take(&mut self) -> (Vec<u8>, Vec<u8>) {
+        if self.waiting().is_empty() {
+            self.progress = Instant::now();
+        }
+        self.replies.drain(..self.written);
+        self.written = 0;
+        (
+            std::mem::take(&mut self.buffer),
+            std::mem::take(&mut self.replies),
+        )
+    }
+
+    /// Put back the input left over and the replies, with new ones queued
+    pub fn restore(
+        &mut self,
+        mut buffer: Vec<u8>,
+        replies: Vec<u8>,
+        state: &State,
+        client: &Client,
+    ) {
+        // Give back memory from large requests, idle connections should stay cheap
+        if buffer.is_empty() && buffer.capacity() > READ_SIZE {
+            buffer = Vec::new();
+        }
+        self.buffer = buffer;
+        self.replies = replies;
+        self.update(state, client);
+    }
+
+    /// Record how many replies are waiting, and since when they are beyond the soft limit
+    fn update(&mut self, state: &State, client: &Client) {
+        let soft_limit = state.config.read().unwrap().client_output_buffer_soft_limit;
+        let waiting = self.waiting().len();
+        client.record_output(waiting);
+
+        if soft_limit == 0 || waiting <= soft_limit {
+            self.over_soft_limit = None;
+        } else if self.over_soft_limit.is_none() {
+            self.over_soft_limit = Some(Instant::now());
+        }
+
+        if waiting == 0 {
+            self.replies.clear();
+            self.written = 0;
+            if self.replies.capacity() > READ_SIZE {
+                self.replies = Vec::new();
+            }
+        }
+    }
+
+    /// When the client is disconnected for not reading its replies, `None` while it may take as
+    /// long as it likes
+    fn write_deadline(&self, state: &State) -> Option<Instant> {
+        if self.waiting().is_empty() {
+            return None;
+        }
+
+        let config = state.config.read().unwrap();
+        let write_timeout = config.client_write_timeout;
+        let stalled =
+            (write_timeout > 0).then(|| self.progress + Duration::from_secs(write_timeout));
+        let soft_seconds = Duration::from_secs(config.client_output_buffer_soft_seconds);
+        let over_soft_limit = self.over_soft_limit.map(|since| since + soft_seconds);
+        stalled.into_iter().chain(over_soft_limit).min()
+    }
+
+    /// Wait for input, progress writing the replies, or a reason to close the connection. Input
+    /// is read while replies are waiting, so a client that does not read them reaches the output
+    /// buffer hard limit rather than holding up the server
+    pub async fn next_event(&mut self, state: &State, client: &Client) -> Event {
+        let mut shutdown = state.shutdown.subscribe();
+        let timeout = state.config.read().unwrap().timeout;
+        let idle = (timeout > 0 && self.waiting().is_empty())
+            .then(|| self.last_input + Duration::from_secs(timeout));
+        let stalled = self.write_deadline(state);
+        let waiting = &self.replies[self.written..];
+        self.buffer.reserve(READ_SIZE);
+
+        let event = tokio::select! {
+            read = self.reader.read_buf(&mut self.buffer), if !self.closing => Event::Read(read),
+            written = self.writer.write(waiting), if !waiting.is_empty() => {
+                match written {
+                    Ok(0) => Event::Written(Err(io::ErrorKind::WriteZero.into())),
+                    written => Event::Written(written),
+                }
+            }
+            _ = shutdown.wait_for(Option::is_some), if !self.closing => Event::Shutdown,
+            _ = client.killed(), if !self.closing => Event::Killed,
+            _ = sleep_until(idle.unwrap_or_else(Instant::now)), if idle.is_some() => Event::Idle,
+            _ = sleep_until(stalled.unwrap_or_else(Instant::now)), if stalled.is_some() => {
+                Event::Stalled
+            }
+        };
+
+        match event {
+            Event::Read(Ok(_)) => self.last_input = Instant::now(),
+            Event::Written(Ok(written)) => {
+                self.written += written;
+                self.progress = Instant::now();
+                client.record_written(written);
+                self.update(state, client);
+            }
+            _ => (),
+        }
+        event
+    }
+
+    /// Close the connection, its waiting replies are dropped
+    pub async fn shutdown(&mut self) {
+        let _ = self.writer.shutdown().await;
+    }
+}
+
+/// Handle every command in `buffer`, returning how many bytes were consumed and whether to keep
+/// serving the connection
+fn handle_buffer(
+    buffer: &[u8],
+    session: &mut Session,
+    state: &State,
+    out: &mut Vec<u8>,
+) -> (usize, Flow) {
+    let mut remaining = buffer;
+    let (limits, hard_limit) = {
+        let config = state.config.read().unwrap();
+        (config.limits(), config.client_output_buffer_hard_limit)
+    };
+
+    loop {
+        let (rest, flow) = match parse_command_with(remaining, &limits) {
+            Ok((rest, command)) => {
+                debug!(command = ?command, size = remaining.len() - rest.len(), "handling command");
+                session.client.record(command.name());
+                match check_access(&command, session, state) {
+                    Ok(()) => (rest, handle_command(&command, session, state, out)),
+                    Err(message) => {
+                        debug!(err = message, "command denied");
+                        reply(out, session.protocol, &Data::Error(&message));
+                        (rest, Flow::Continue)
+                    }
+                }
+            }
+            Err(ParseError::Incomplete) => break,
+            Err(ParseError::Command { remaining, error }) => {
+                debug!(err = %error, "invalid command");
+                let message = format!("ERR {}", error);
+                reply(out, session.protocol, &Data::Error(&message));
+                (remaining, Flow::Continue)
+            }
+            Err(ParseError::Protocol(error)) => {
+                debug!(err = error, "protocol error, closing connection");
+                let message = format!("ERR Protocol error: {}", error);
+                reply(out, session.protocol, &Data::Error(&message));
+                (remaining, Flow::Close)
+            }
+        };
+
+        remaining = rest;
+        if flow == Flow::Close || output_limit_reached(out, hard_limit) {
+            return (buffer.len() - remaining.len(), Flow::Close);
+        }
+    }
+
+    (buffer.len() - remaining.len(), Flow::Continue)
+}
+
+/// Serve a RESP client until it disconnects. Every command read in one go is answered together,
+/// while the client reads the replies more commands are handled
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    state: &Arc<State>,
+    client: Option<Arc<Client>>,
+) {
+    info!("new connection");
+    // Clients beyond maxclients are not registered
+    let Some(client) = client else {
+        debug!("max number of clients reached, refusing connection");
+        let _ = stream
+            .write_all(b"-ERR max number of clients reached\r\n")
+            .await;
+        let _ = stream.shutdown().await;
+        return;
+    };
+
+    let mut connection = Connection::new(stream);
+    let mut session = Session::new(state, client);
+
+    loop {
+        match connection.next_event(state, &session.client).await {
+            Event::Read(Err(err)) => {
+                debug!(err = %err, "failed to read from stream");
+                break;
+            }
+            Event::Read(Ok(0)) => {
+                debug!("client closed connection");
+                break;
+            }
+            Event::Read(Ok(read)) => {
+                // Commands block on the store and its file I/O, so they are kept off the async
+                // workers
+                let (buffer, mut out) = connection.take();
+                let task_state = Arc::clone(state);
+                let handled = tokio::task::spawn_blocking(move || {
+                    let (consumed, flow) =
+                        handle_buffer(&buffer, &mut session, &task_state, &mut out);
+                    (buffer, session, out, consumed, flow)
+                })
+                .await;
+                let (mut buffer, mut out, consumed, mut flow);
+                match handled {
+                    Ok(handled) => (buffer, session, out, consumed, flow) = handled,
+                    Err(err) => {
+                        error!(err = %err, "failed to handle commands");
+                        break;
+                    }
+                }
+                buffer.drain(..consumed);
+                session.client.record_input(read, buffer.len());
+
+                // Every part of the request may be within limits while the whole is not
+                let query_buffer_limit = state.config.read().unwrap().client_query_buffer_limit;
+                if flow == Flow::Continue && buffer.len() > query_buffer_limit {
+                    debug!(
+                        bytes = buffer.len(),
+                        "query buffer limit reached, closing connection"
+                    );
+                    let message = "ERR Protocol error: request exceeds protocol limits";
+                    reply(&mut out, session.protocol, &Data::Error(message));
+                    flow = Flow::Close;
+                }
+
+                connection.restore(buffer, out, state, &session.client);
+                connection.closing |= flow == Flow::Close;
+            }
+            Event::Written(Err(err)) => {
+                debug!(err = %err, "failed to write to stream");
+                break;
+            }
+            Event::Written(Ok(_)) => (),
+            // Commands already read are answered before the connection is closed
+            Event::Shutdown => {
+                debug!("server shutting down, closing connection");
+                connection.closing = true;
+            }
+            Event::Killed => {
+                debug!("client killed, closing connection");
+                connection.closing = true;
+            }
+            Event::Idle => {
+                debug!("client idle for too long, closing connection");
+                break;
+            }
+            Event::Stalled => {
+                debug!(
+                    bytes = connection.waiting().len(),
+                    "client not reading its replies, closing connection"
+                );
+                break;
+            }
+        }
+
+        if connection.closing && connection.waiting().is_empty() {
+            connection.shutdown().await;
+            break;
+        }
+    }
+
+    debug!("client going away");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Serve a client over an in-memory stream holding at most `capacity` unread bytes, with a
+    /// value of `size` bytes stored at `big`
+    fn serve(
+        name: &str,
+        config: Config,
+        size: usize,
+        capacity: usize,
+    ) -> (
+        tokio::io::DuplexStream,
+        Arc<Client>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let state = Arc::new(State::for_test(name, config));
+        state
+            .bitcask
+            .lock()
+            .unwrap()
+            .put("big", &vec![b'x'; size])
+            .unwrap();
+        let client = state.clients.register(name.to_string(), 1).unwrap();
+
+        let (client_stream, server_stream) = tokio::io::duplex(capacity);
+        let served = Arc::clone(&client);
+        let server = tokio::spawn(async move {
+            handle_client(server_stream, &state, Some(served)).await;
+            let data_dir = state.config.read().unwrap().data_dir.clone();
+            std::fs::remove_dir_all(data_dir).unwrap();
+        });
+        (client_stream, client, server)
+    }
+
+    #[test]
+    fn test_hello_auth_and_setname() {
+        let config = Config {
+            requirepass: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let state = State::for_test("resp-hello", config);
+        let client = state.clients.register("resp-hello".to_string(), 1).unwrap();
+        let mut session = Session::new(&state, client);
+        assert_eq!(session.user, None);
+
+        let hello = |password: &'static str| {
+            Command::Hello(Hello {
+                version: Some("3".into()),
+                auth: Some(("default".into(), password.into())),
+                name: Some("worker".into()),
+            })
+        };
+
+        // Nothing changes when authentication fails
+        let mut out = Vec::new();
+        handle_command(&hello("wrong"), &mut session, &state, &mut out);
+        assert!(out.starts_with(b"-WRONGPASS"));
+        assert_eq!(session.user, None);
+        assert_eq!(session.protocol, Protocol::Resp2);
+        assert_eq!(session.client.name(), None);
+
+        let mut out = Vec::new();
+        handle_command(&hello("secret"), &mut session, &state, &mut out);
+        assert!(out.starts_with(b"%"));
+        assert_eq!(session.user.as_deref(), Some("default"));
+        assert_eq!(session.protocol, Protocol::Resp3);
+        assert_eq!(session.client.name().as_deref(), Some("worker"));
+
+        let data_dir = state.config.read().unwrap().data_dir.clone();
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands() {
+        let (mut stream, _, server) = serve("resp-pipelined", Config::default(), 1, 1024);
+        stream
+            .write_all(b"SET a 1\r\nGET a\r\nGET big\r\n")
+            .await
+            .unwrap();
+
+        let mut replies = vec![0; 19];
+        stream.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, b"+OK\r\n$1\r\n1\r\n$1\r\nx\r\n");
+        drop(stream);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_buffer_hard_limit() {
+        let config = Config {
+            client_output_buffer_hard_limit: 16 * 1024,
+            client_output_buffer_soft_limit: 0,
+            client_write_timeout: 0,
+            ..Config::default()
+        };
+        let (stream, _, server) = serve("resp-hard-limit", config, 1000, 1024);
+
+        // The client keeps sending commands one at a time but never reads a reply, replies
+        // waiting from earlier commands count towards the limit
+        let (_reader, mut writer) = tokio::io::split(stream);
+        let sender = tokio::spawn(async move {
+            let mut sent = 0;
+            while writer.write_all(b"GET big\r\n").await.is_ok() {
+                sent += 1;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            sent
+        });
+
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("client disconnected at the hard limit")
+            .unwrap();
+        assert!(sender.await.unwrap() > 16);
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let config = Config {
+            client_write_timeout: 1,
+            timeout: 0,
+            ..Config::default()
+        };
+        let (mut stream, client, server) = serve("resp-write-timeout", config, 2000, 1024);
+        let started = Instant::now();
+        stream.write_all(b"GET big\r\n").await.unwrap();
+
+        // The reply fills the stream, what does not fit waits to be read
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(client.describe().contains(" omem=985 "));
+
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("client disconnected for not reading")
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,202 @@
+//! TLS for client connections, terminated with rustls
+
+use serde::{Deserialize, Serialize};
+use std::{io, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Whether clients must present a certificate signed by the CA
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthClients {
+    /// Client certificates are not requested
+    #[default]
+    No,
+    /// Clients without a valid certificate are refused
+    Yes,
+    /// Clients may connect without a certificate, but one that is presented must be valid
+    Optional,
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), err),
+    )
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|err| invalid(path, err))
+}
+
+/// Build the acceptor for TLS connections from PEM encoded files
+///   `ca_cert_file` holds the CA client certificates are verified against
+pub fn acceptor(
+    cert_file: &Path,
+    key_file: &Path,
+    ca_cert_file: Option<&Path>,
+    auth_clients: AuthClients,
+) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|err| invalid(key_file, err))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let builder = match (auth_clients, ca_cert_file) {
+        (AuthClients::No, _) => builder.with_no_client_auth(),
+        (_, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "authenticating clients requires a CA certificate",
+            ))
+        }
+        (auth_clients, Some(ca_cert_file)) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_cert_file)? {
+                roots.add(cert).map_err(|err| invalid(ca_cert_file, err))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth_clients {
+                AuthClients::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(
+                verifier.build().map_err(|err| invalid(ca_cert_file, err))?,
+            )
+        }
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(cert_file, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig},
+        TlsConnector,
+    };
+
+    fn issue(
+        ca: &CertifiedKey,
+        names: &[&str],
+        usage: ExtendedKeyUsagePurpose,
+    ) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &ca.cert, &ca.key_pair).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn load_certs_from(pem: &str) -> Vec<CertificateDer<'static>> {
+        CertificateDer::pem_slice_iter(pem.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// Handshake with the acceptor over an in-memory stream, echoing a byte when it succeeds
+    async fn handshake(acceptor: TlsAcceptor, client: ClientConfig) -> bool {
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_stream).await.ok()?;
+            let byte = stream.read_u8().await.ok()?;
+            stream.write_u8(byte).await.ok()
+        });
+
+        let connector = TlsConnector::from(Arc::new(client));
+        let name = ServerName::try_from("localhost").unwrap();
+        let echoed = async {
+            let mut stream = connector.connect(name, client_stream).await.ok()?;
+            stream.write_u8(42).await.ok()?;
+            stream.read_u8().await.ok()
+        }
+        .await;
+
+        server.await.unwrap().is_some() && echoed == Some(42)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("knowsql-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key_pair = KeyPair::generate().unwrap();
+        let ca = CertifiedKey {
+            cert: ca_params.self_signed(&key_pair).unwrap(),
+            key_pair,
+        };
+        let (cert, key) = issue(&ca, &["localhost"], ExtendedKeyUsagePurpose::ServerAuth);
+        let (client_cert, client_key) = issue(&ca, &[], ExtendedKeyUsagePurpose::ClientAuth);
+
+        for (name, contents) in [
+            ("ca.pem", ca.cert.pem()),
+            ("cert.pem", cert),
+            ("key.pem", key),
+        ] {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let ca_file = dir.join("ca.pem");
+        let acceptor = |auth_clients| {
+            acceptor(
+                &dir.join("cert.pem"),
+                &dir.join("key.pem"),
+                Some(&ca_file),
+                auth_clients,
+            )
+            .unwrap()
+        };
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let builder = || {
+            ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots.clone())
+        };
+        let anonymous = builder().with_no_client_auth();
+        let authenticated = builder()
+            .with_client_auth_cert(
+                load_certs_from(&client_cert),
+                PrivateKeyDer::from_pem_slice(client_key.as_bytes()).unwrap(),
+            )
+            .unwrap();
+
+        assert!(handshake(acceptor(AuthClients::No), anonymous.clone()).await);
+        assert!(handshake(acceptor(AuthClients::Yes), authenticated.clone()).await);
+        assert!(!handshake(acceptor(AuthClients::Yes), anonymous.clone()).await);
+        assert!(handshake(acceptor(AuthClients::Optional), anonymous).await);
+        assert!(handshake(acceptor(AuthClients::Optional), authenticated).await);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
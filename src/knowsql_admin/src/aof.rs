@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::Read;
+use std::process::ExitCode;
+
+use knowsql_bitcask::BitCask;
+use knowsql_parser::{command::Command, parse_command, ParseError};
+
+/// Outcome of [`replay()`]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReplaySummary {
+    /// Commands applied to the store
+    pub applied: u64,
+    /// Commands that do not modify the store
+    pub ignored: u64,
+    /// Bytes at the end of the log that do not form a complete command
+    pub trailing_bytes: usize,
+}
+
+/// Rebuild a store by applying every command of an append only file
+pub fn replay<R: Read>(cask: &mut BitCask, mut reader: R) -> Result<ReplaySummary, String> {
+    let mut summary = ReplaySummary::default();
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    // Position of the start of `buffer` in the log
+    let mut offset = 0;
+
+    loop {
+        let read = reader
+            .read(&mut chunk)
+            .map_err(|err| format!("failed to read log: {}", err))?;
+        if read == 0 {
+            summary.trailing_bytes = buffer.len();
+            return Ok(summary);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut consumed = 0;
+        loop {
+            let (remaining, command) = match parse_command(&buffer[consumed..]) {
+                Ok(parsed) => parsed,
+                Err(ParseError::Incomplete) => break,
+                Err(ParseError::Command { error, .. }) => {
+                    return Err(format!(
+                        "invalid command at byte {}: {}",
+                        offset + consumed,
+                        error
+                    ))
+                }
+                Err(ParseError::Protocol(error)) => {
+                    return Err(format!(
+                        "protocol error at byte {}: {}",
+                        offset + consumed,
+                        error
+                    ))
+                }
+            };
+            consumed = buffer.len() - remaining.len();
+
+            match command {
+                Command::Set(key, value) => {
+                    cask.put(&key, &value)
+                        .map_err(|err| format!("failed to set {}: {}", key, err))?;
+                    summary.applied += 1;
+                }
+                Command::Del(keys) => {
+                    for key in keys {
+                        cask.delete(&key)
+                            .map_err(|err| format!("failed to delete {}: {}", key, err))?;
+                    }
+                    summary.applied += 1;
+                }
+                Command::FlushAll => {
+                    cask.clear()
+                        .map_err(|err| format!("failed to flush keys: {}", err))?;
+                    summary.applied += 1;
+                }
+                _ => summary.ignored += 1,
+            }
+        }
+
+        buffer.drain(..consumed);
+        offset += consumed;
+    }
+}
+
+/// `replay-aof <data_dir> <file>`
+pub fn run(args: &[String]) -> Result<ExitCode, String> {
+    let [data_dir, path] = args else {
+        return Err("usage: knowsql-admin replay-aof <data_dir> <file>".into());
+    };
+
+    let mut cask = crate::dump::open(data_dir)?;
+    let file = File::open(path).map_err(|err| format!("failed to open {}: {}", path, err))?;
+    let summary = replay(&mut cask, file)?;
+
+    eprintln!(
+        "applied {} commands, ignored {}",
+        summary.applied, summary.ignored
+    );
+    if summary.trailing_bytes > 0 {
+        eprintln!(
+            "ignored {} bytes at the end of the log that do not form a command",
+            summary.trailing_bytes
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let mut log = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec();
+        log.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+        log.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n2\r\n");
+        // The server stopped part way through appending a command
+        log.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+
+        let dir = std::env::temp_dir().join(format!("knowsql-aof-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+
+        let summary = replay(&mut cask, &log[..]).unwrap();
+        assert_eq!(
+            summary,
+            ReplaySummary {
+                applied: 2,
+                ignored: 1,
+                trailing_bytes: 18,
+            }
+        );
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+        assert!(!cask.contains_key("b"));
+
+        cask.put("b", b"1").unwrap();
+        let summary = replay(&mut cask, &b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n"[..]).unwrap();
+        assert_eq!(summary.applied, 1);
+        assert!(!cask.contains_key("a"));
+        drop(cask);
+
+        // Deleted keys stay gone once the store is reopened
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert!(!cask.contains_key("a"));
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("1"));
+        replay(&mut cask, &b"*1\r\n$8\r\nFLUSHALL\r\n"[..]).unwrap();
+        assert!(cask.is_empty());
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert!(cask.is_empty());
+
+        let err = replay(&mut cask, &b"*1\r\n$3\r\nFOO\r\n"[..]).unwrap_err();
+        assert!(err.starts_with("invalid command at byte 0"), "{}", err);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
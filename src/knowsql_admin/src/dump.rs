@@ -0,0 +1,62 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use knowsql_bitcask::{dump, BitCask};
+
+pub fn open(data_dir: &str) -> Result<BitCask, String> {
+    BitCask::open(PathBuf::from(data_dir))
+        .map_err(|err| format!("failed to open {}: {}", data_dir, err))
+}
+
+/// `dump <data_dir> [file]`
+///   writes to stdout when no file is given
+pub fn run_dump(args: &[String]) -> Result<ExitCode, String> {
+    let (data_dir, path) = match args {
+        [data_dir] => (data_dir, None),
+        [data_dir, path] => (data_dir, Some(path)),
+        _ => return Err("usage: knowsql-admin dump <data_dir> [file]".into()),
+    };
+    let cask = open(data_dir)?;
+
+    let entries = match path.filter(|path| *path != "-") {
+        Some(path) => {
+            let file =
+                File::create(path).map_err(|err| format!("failed to create {}: {}", path, err))?;
+            dump::export(&cask, BufWriter::new(file))
+        }
+        None => dump::export(&cask, BufWriter::new(std::io::stdout().lock())),
+    }
+    .map_err(|err| format!("failed to dump: {}", err))?;
+
+    eprintln!("dumped {} entries", entries);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// `restore <data_dir> [file]`
+///   reads from stdin when no file is given
+pub fn run_restore(args: &[String]) -> Result<ExitCode, String> {
+    let (data_dir, path) = match args {
+        [data_dir] => (data_dir, None),
+        [data_dir, path] => (data_dir, Some(path)),
+        _ => return Err("usage: knowsql-admin restore <data_dir> [file]".into()),
+    };
+    let mut cask = open(data_dir)?;
+
+    let summary = match path.filter(|path| *path != "-") {
+        Some(path) => {
+            let file =
+                File::open(path).map_err(|err| format!("failed to open {}: {}", path, err))?;
+            dump::import(&mut cask, BufReader::new(file))
+        }
+        None => dump::import(&mut cask, std::io::stdin().lock()),
+    }
+    .map_err(|err| format!("failed to restore: {}", err))?;
+
+    eprintln!(
+        "restored {} entries, skipped {} expired, dropped expiry of {}",
+        summary.imported, summary.expired, summary.expiry_dropped
+    );
+    Ok(ExitCode::SUCCESS)
+}
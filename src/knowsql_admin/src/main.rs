@@ -0,0 +1,43 @@
+//! Offline administration of knowsql data directories
+
+mod aof;
+mod dump;
+mod rdb;
+mod verify;
+
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: knowsql-admin <command> [args]
+
+commands:
+    verify <data_dir> [--repair truncate|rewrite]
+        check every data file and optionally repair corrupt ones
+    dump <data_dir> [file]
+        write every key-value pair to file, or stdout
+    restore <data_dir> [file]
+        put every key-value pair of a dump from file, or stdin
+    import-rdb <data_dir> <file> [--db <index>]
+        put every string key of a Redis RDB file, from database 0 unless given
+    replay-aof <data_dir> <file>
+        apply every command of an append only file";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("verify") => verify::run(&args[1..]),
+        Some("dump") => dump::run_dump(&args[1..]),
+        Some("restore") => dump::run_restore(&args[1..]),
+        Some("import-rdb") => rdb::run(&args[1..]),
+        Some("replay-aof") => aof::run(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::from(2)
+        }
+    }
+}
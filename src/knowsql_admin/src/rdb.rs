@@ -0,0 +1,559 @@
+//! Loader for Redis RDB dump files
+//!   https://rdb.fnordig.de/file_format.html
+//!
+//! Only string keys holding utf8 values are stored, keys of any other type are skipped and
+//! counted per type.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::process::ExitCode;
+
+use chrono::Utc;
+use knowsql_bitcask::BitCask;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+
+/// Highest RDB version this loader understands
+const MAX_VERSION: u32 = 12;
+
+/// Most bytes reserved up front for a decompressed string, the length comes from the file
+const MAX_PREALLOCATION: usize = 1024 * 1024;
+
+/// Outcome of [`load()`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RdbSummary {
+    pub imported: u64,
+    /// Keys skipped because their expiry had already passed
+    pub expired: u64,
+    /// Keys imported without their expiry, as the store does not support expiring keys
+    pub expiry_dropped: u64,
+    /// Keys skipped because they belong to a database other than the one being loaded
+    pub other_databases: u64,
+    /// Keys skipped because the key is not a valid utf8 string
+    pub invalid_keys: u64,
+    /// Keys skipped because the value is not a valid utf8 string
+    pub invalid_values: u64,
+    /// Keys skipped per unsupported value type
+    pub unsupported: BTreeMap<&'static str, u64>,
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn type_name(value_type: u8) -> Option<&'static str> {
+    Some(match value_type {
+        0 => "string",
+        1 | 10 | 14 | 18 => "list",
+        2 | 11 | 20 => "set",
+        3 | 5 | 12 | 17 => "zset",
+        4 | 9 | 13 | 16 | 24 | 25 => "hash",
+        15 | 19 | 21 => "stream",
+        _ => return None,
+    })
+}
+
+/// CRC-64/Jones as used by Redis, updated byte by byte
+fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+    for byte in bytes {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Decompress a LZF compressed string
+fn lzf_decompress(input: &[u8], size: usize) -> std::io::Result<Vec<u8>> {
+    let corrupt = || invalid_data("corrupt lzf compressed string".to_string());
+    let mut output = Vec::with_capacity(size.min(MAX_PREALLOCATION));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            if output.len() + literal.len() > size {
+                return Err(corrupt());
+            }
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+            continue;
+        }
+
+        let mut len = ctrl >> 5;
+        if len == 7 {
+            len += *input.get(i).ok_or_else(corrupt)? as usize;
+            i += 1;
+        }
+        let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+        i += 1;
+
+        let start = output.len().checked_sub(offset).ok_or_else(corrupt)?;
+        if output.len() + len + 2 > size {
+            return Err(corrupt());
+        }
+        // Back references may overlap the bytes they produce, so copy one at a time
+        for j in 0..len + 2 {
+            output.push(output[start + j]);
+        }
+    }
+
+    if output.len() != size {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+enum Length {
+    Len(u64),
+    /// A string stored in a special format, see [`RdbReader::read_string()`]
+    Encoded(u8),
+}
+
+struct RdbReader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.crc = crc64(self.crc, buf);
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_bytes(&mut self, size: u64) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(size).read_to_end(&mut buf)?;
+        if buf.len() as u64 != size {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn read_length_encoding(&mut self) -> std::io::Result<Length> {
+        let first = self.read_u8()?;
+
+        Ok(match first >> 6 {
+            0b00 => Length::Len((first & 0x3f) as u64),
+            0b01 => Length::Len((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64),
+            0b10 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.read_array()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.read_array()?)),
+                _ => {
+                    return Err(invalid_data(format!(
+                        "invalid length prefix {:#04x}",
+                        first
+                    )))
+                }
+            },
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn read_length(&mut self) -> std::io::Result<u64> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid_data("expected a length".to_string())),
+        }
+    }
+
+    fn read_string(&mut self) -> std::io::Result<Vec<u8>> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => self.read_bytes(len),
+            Length::Encoded(0) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => Ok(i16::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(2) => Ok(i32::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(3) => {
+                let compressed_size = self.read_length()?;
+                let size = self.read_length()?;
+                let compressed = self.read_bytes(compressed_size)?;
+                lzf_decompress(&compressed, size as usize)
+            }
+            Length::Encoded(format) => {
+                Err(invalid_data(format!("unknown string encoding {}", format)))
+            }
+        }
+    }
+
+    fn skip_strings(&mut self, count: u64) -> std::io::Result<()> {
+        for _ in 0..count {
+            self.read_string()?;
+        }
+        Ok(())
+    }
+
+    fn skip_stream_id(&mut self) -> std::io::Result<()> {
+        self.read_length()?;
+        self.read_length()?;
+        Ok(())
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> std::io::Result<()> {
+        let listpacks = self.read_length()?;
+        self.skip_strings(listpacks * 2)?;
+
+        // items, last id
+        self.read_length()?;
+        self.skip_stream_id()?;
+        if value_type >= 19 {
+            // first id, max deleted id, entries added
+            self.skip_stream_id()?;
+            self.skip_stream_id()?;
+            self.read_length()?;
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            self.skip_stream_id()?;
+            if value_type >= 19 {
+                // entries read
+                self.read_length()?;
+            }
+
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                // id, delivery time, delivery count
+                self.read_array::<16>()?;
+                self.read_array::<8>()?;
+                self.read_length()?;
+            }
+
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                // seen time, and active time from type 21
+                self.read_array::<8>()?;
+                if value_type >= 21 {
+                    self.read_array::<8>()?;
+                }
+
+                let pending = self.read_length()?;
+                for _ in 0..pending {
+                    self.read_array::<16>()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read past a value that is not stored
+    fn skip_value(&mut self, value_type: u8) -> std::io::Result<()> {
+        match value_type {
+            // list, set
+            1 | 2 => {
+                let len = self.read_length()?;
+                self.skip_strings(len)
+            }
+            // zset, with scores stored as strings prefixed by a one byte length
+            3 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    let score_len = self.read_u8()?;
+                    if score_len < 253 {
+                        self.read_bytes(score_len as u64)?;
+                    }
+                }
+                Ok(())
+            }
+            // hash
+            4 => {
+                let len = self.read_length()?;
+                self.skip_strings(len * 2)
+            }
+            // zset, with binary double scores
+            5 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    self.read_array::<8>()?;
+                }
+                Ok(())
+            }
+            // encoded as a single blob
+            9..=13 | 16 | 17 | 20 => self.skip_strings(1),
+            // quicklist
+            14 => {
+                let len = self.read_length()?;
+                self.skip_strings(len)
+            }
+            15 | 19 | 21 => self.skip_stream(value_type),
+            // quicklist with container kinds
+            18 => {
+                for _ in 0..self.read_length()? {
+                    self.read_length()?;
+                    self.read_string()?;
+                }
+                Ok(())
+            }
+            // hash with field expiry
+            24 => {
+                self.read_array::<8>()?;
+                for _ in 0..self.read_length()? {
+                    self.read_length()?;
+                    self.skip_strings(2)?;
+                }
+                Ok(())
+            }
+            // listpack hash with field expiry
+            25 => {
+                self.read_array::<8>()?;
+                self.skip_strings(1)
+            }
+            _ => Err(invalid_data(format!(
+                "value type {} cannot be skipped",
+                value_type
+            ))),
+        }
+    }
+}
+
+/// Put every string key of database `db` within a RDB file into the store
+pub fn load<R: Read>(cask: &mut BitCask, reader: R, db: u64) -> std::io::Result<RdbSummary> {
+    let mut reader = RdbReader {
+        inner: reader,
+        crc: 0,
+    };
+
+    let magic: [u8; 9] = reader.read_array()?;
+    if &magic[..5] != b"REDIS" {
+        return Err(invalid_data("not a RDB file".to_string()));
+    }
+    let version: u32 = std::str::from_utf8(&magic[5..])
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| invalid_data("invalid RDB version".to_string()))?;
+    if version > MAX_VERSION {
+        return Err(invalid_data(format!("unsupported RDB version {}", version)));
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let mut summary = RdbSummary::default();
+    let mut current_db = 0;
+    let mut expire_at = None;
+
+    loop {
+        let opcode = reader.read_u8()?;
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => current_db = reader.read_length()?,
+            OPCODE_RESIZEDB => {
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            OPCODE_SLOT_INFO => {
+                reader.read_length()?;
+                reader.read_length()?;
+                reader.read_length()?;
+            }
+            OPCODE_AUX => reader.skip_strings(2)?,
+            OPCODE_FUNCTION2 => reader.skip_strings(1)?,
+            OPCODE_MODULE_AUX => {
+                return Err(invalid_data("module data is not supported".to_string()))
+            }
+            OPCODE_IDLE => {
+                reader.read_length()?;
+            }
+            OPCODE_FREQ => {
+                reader.read_u8()?;
+            }
+            OPCODE_EXPIRETIME => {
+                expire_at = Some(u32::from_le_bytes(reader.read_array()?) as i64 * 1000)
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expire_at = Some(i64::from_le_bytes(reader.read_array()?));
+            }
+            value_type => {
+                let type_name = type_name(value_type).ok_or_else(|| {
+                    invalid_data(format!("unsupported value type {}", value_type))
+                })?;
+                let key = reader.read_string()?;
+                let expire_at = expire_at.take();
+
+                if value_type != TYPE_STRING {
+                    reader.skip_value(value_type)?;
+                    if current_db == db {
+                        *summary.unsupported.entry(type_name).or_default() += 1;
+                    }
+                    continue;
+                }
+
+                let value = reader.read_string()?;
+                if current_db != db {
+                    summary.other_databases += 1;
+                    continue;
+                }
+                let Ok(key) = String::from_utf8(key) else {
+                    summary.invalid_keys += 1;
+                    continue;
+                };
+                if std::str::from_utf8(&value).is_err() {
+                    summary.invalid_values += 1;
+                    continue;
+                }
+
+                match expire_at {
+                    Some(expire_at) if expire_at <= now => {
+                        summary.expired += 1;
+                        continue;
+                    }
+                    Some(_) => summary.expiry_dropped += 1,
+                    None => (),
+                }
+
+                cask.put(&key, &value)?;
+                summary.imported += 1;
+            }
+        }
+    }
+
+    if version >= 5 {
+        let expected = reader.crc;
+        let checksum = u64::from_le_bytes(reader.read_array()?);
+        // A checksum of 0 means it was disabled when the file was written
+        if checksum != 0 && checksum != expected {
+            return Err(invalid_data("checksum mismatch".to_string()));
+        }
+    }
+
+    Ok(summary)
+}
+
+/// `import-rdb <data_dir> <file> [--db <index>]`
+pub fn run(args: &[String]) -> Result<ExitCode, String> {
+    let (data_dir, path, db) = match args {
+        [data_dir, path] => (data_dir, path, 0),
+        [data_dir, path, flag, db] if flag == "--db" => match db.parse() {
+            Ok(db) => (data_dir, path, db),
+            Err(_) => return Err(format!("invalid database index '{}'", db)),
+        },
+        _ => return Err("usage: knowsql-admin import-rdb <data_dir> <file> [--db <index>]".into()),
+    };
+
+    let mut cask = crate::dump::open(data_dir)?;
+    let file = File::open(path).map_err(|err| format!("failed to open {}: {}", path, err))?;
+    let summary = load(&mut cask, BufReader::new(file), db)
+        .map_err(|err| format!("failed to import {}: {}", path, err))?;
+
+    eprintln!(
+        "imported {} keys, skipped {} expired, dropped expiry of {}",
+        summary.imported, summary.expired, summary.expiry_dropped
+    );
+    if summary.other_databases > 0 {
+        eprintln!(
+            "skipped {} keys in other databases",
+            summary.other_databases
+        );
+    }
+    if summary.invalid_keys > 0 {
+        eprintln!("skipped {} keys that are not utf8", summary.invalid_keys);
+    }
+    if summary.invalid_values > 0 {
+        eprintln!(
+            "skipped {} keys whose value is not utf8",
+            summary.invalid_values
+        );
+    }
+    for (type_name, count) in &summary.unsupported {
+        eprintln!("skipped {} keys of unsupported type {}", count, type_name);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        assert_eq!(
+            lzf_decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 10).unwrap(),
+            b"aaaaaaaaaa"
+        );
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+        // Output beyond the announced size is rejected rather than allocated
+        assert!(lzf_decompress(&[0x00, b'a', 0xe0, 0x00, 0x00], 4).is_err());
+        assert!(lzf_decompress(&[0x01, b'a', b'b'], 1).is_err());
+        assert!(lzf_decompress(&[0x00, b'a'], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.extend_from_slice(b"\xfa\x09redis-ver\x057.2.4");
+        rdb.extend_from_slice(b"\xfe\x00\xfb\x04\x01");
+        rdb.extend_from_slice(b"\x00\x05hello\x05world");
+        rdb.extend_from_slice(b"\x00\x03int\xc1\x39\x30");
+        rdb.extend_from_slice(b"\x00\x03lzf\xc3\x05\x0a\x00a\xe0\x00\x00");
+        rdb.extend_from_slice(b"\x00\x06binary\x02\xff\xfe");
+        rdb.extend_from_slice(b"\xfc\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03old\x01x");
+        rdb.extend_from_slice(b"\x01\x04list\x02\x01a\x01b");
+        rdb.extend_from_slice(b"\xfe\x01\x00\x05other\x01x");
+        rdb.push(OPCODE_EOF);
+        rdb.extend_from_slice(&crc64(0, &rdb).to_le_bytes());
+
+        let dir = std::env::temp_dir().join(format!("knowsql-rdb-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+
+        let summary = load(&mut cask, &rdb[..], 0).unwrap();
+        assert_eq!(summary.imported, 3);
+        assert_eq!(summary.expired, 1);
+        assert_eq!(summary.other_databases, 1);
+        assert_eq!(summary.invalid_values, 1);
+        assert_eq!(summary.unsupported, BTreeMap::from([("list", 1)]));
+        assert_eq!(cask.get("hello").unwrap().as_deref(), Some("world"));
+        assert_eq!(cask.get("int").unwrap().as_deref(), Some("12345"));
+        assert_eq!(cask.get("lzf").unwrap().as_deref(), Some("aaaaaaaaaa"));
+        assert!(!cask.contains_key("binary"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
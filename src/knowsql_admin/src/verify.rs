@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use knowsql_bitcask::verify::{self, Repair};
+
+/// `verify <data_dir> [--repair truncate|rewrite]`
+///   exits with 1 when corruption was found and not repaired
+pub fn run(args: &[String]) -> Result<ExitCode, String> {
+    let (data_dir, repair) = match args {
+        [data_dir] => (data_dir, None),
+        [data_dir, flag, mode] if flag == "--repair" => match mode.as_str() {
+            "truncate" => (data_dir, Some(Repair::Truncate)),
+            "rewrite" => (data_dir, Some(Repair::Rewrite)),
+            _ => return Err(format!("unknown repair mode '{}'", mode)),
+        },
+        _ => {
+            return Err("usage: knowsql-admin verify <data_dir> [--repair truncate|rewrite]".into())
+        }
+    };
+    let data_dir = Path::new(data_dir);
+
+    let report = verify::verify(data_dir)
+        .map_err(|err| format!("failed to verify {}: {}", data_dir.display(), err))?;
+
+    for segment in &report.segments {
+        println!(
+            "{}: {} bytes, {} records, {} orphaned ({} bytes){}{}",
+            segment.path.display(),
+            segment.size,
+            segment.records,
+            segment.orphaned_records,
+            segment.orphaned_bytes,
+            if segment.active { "" } else { ", inactive" },
+            if segment.checksums {
+                ""
+            } else {
+                ", no checksums"
+            },
+        );
+        if let Some(corrupt) = &segment.corrupt {
+            println!(
+                "  corrupt bytes {}..{}: {}",
+                corrupt.start, corrupt.end, corrupt.reason
+            );
+        }
+    }
+    for path in &report.stray_files {
+        println!(
+            "{}: stray file left by an interrupted merge",
+            path.display()
+        );
+    }
+
+    if report.is_clean() {
+        println!("ok");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    match repair {
+        Some(repair) => {
+            verify::repair(data_dir, &report, repair)
+                .map_err(|err| format!("failed to repair {}: {}", data_dir.display(), err))?;
+            println!("repaired");
+            Ok(ExitCode::SUCCESS)
+        }
+        None => Ok(ExitCode::FAILURE),
+    }
+}
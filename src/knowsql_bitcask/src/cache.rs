@@ -0,0 +1,69 @@
+//! Bounded LRU cache of values sitting in front of the data file reads
+
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+/// Hit and miss counters of the read cache
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Maximum number of values the cache will hold
+    pub capacity: usize,
+    /// Number of values currently held
+    pub len: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct ReadCache {
+    values: LruCache<String, String>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ReadCache {
+    pub fn new(capacity: NonZeroUsize) -> ReadCache {
+        ReadCache {
+            values: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Lookup a value, counting the outcome as a hit or a miss
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        match self.values.get(key) {
+            Some(value) => {
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, value: String) {
+        self.values.put(key.to_string(), value);
+    }
+
+    /// Drop a cached value, used whenever the value on disk changes
+    pub fn invalidate(&mut self, key: &str) {
+        self.values.pop(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.values.cap().get(),
+            len: self.values.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
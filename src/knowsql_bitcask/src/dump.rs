@@ -0,0 +1,270 @@
+//! Portable dump of every key-value pair in a store
+//!
+//! A dump is a stream of big endian, length prefixed records;
+//!
+//! ```text
+//! header: "KNOWSQL-DUMP" version:u8
+//! entry:  0x01 key_size:u32 key value_size:u32 value expire_at:i64
+//! end:    0xFF entries:u64
+//! ```
+//!
+//! `expire_at` is a unix timestamp in milliseconds, or `-1` when the key never expires.
+//! The end record carries the number of entries written so truncated dumps are detected.
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use chrono::Utc;
+
+use crate::BitCask;
+
+pub const MAGIC: &[u8; 12] = b"KNOWSQL-DUMP";
+pub const VERSION: u8 = 1;
+
+const TAG_ENTRY: u8 = 0x01;
+const TAG_END: u8 = 0xFF;
+
+/// A key-value pair read from a dump
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Unix timestamp in milliseconds after which the key should no longer exist
+    pub expire_at: Option<i64>,
+}
+
+/// Outcome of [`import()`]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    /// Entries skipped because their expiry had already passed
+    pub expired: u64,
+    /// Entries imported without their expiry, as the store does not support expiring keys
+    pub expiry_dropped: u64,
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes a dump to the underlying writer, entry by entry
+pub struct DumpWriter<W: Write> {
+    inner: W,
+    entries: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub fn new(mut inner: W) -> std::io::Result<DumpWriter<W>> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        Ok(DumpWriter { inner, entries: 0 })
+    }
+
+    pub fn write_entry(
+        &mut self,
+        key: &str,
+        value: &[u8],
+        expire_at: Option<i64>,
+    ) -> std::io::Result<()> {
+        let key_size = u32::try_from(key.len()).map_err(|_| invalid_data("key too large"))?;
+        let value_size = u32::try_from(value.len()).map_err(|_| invalid_data("value too large"))?;
+
+        self.inner.write_all(&[TAG_ENTRY])?;
+        self.inner.write_all(&key_size.to_be_bytes())?;
+        self.inner.write_all(key.as_bytes())?;
+        self.inner.write_all(&value_size.to_be_bytes())?;
+        self.inner.write_all(value)?;
+        self.inner
+            .write_all(&expire_at.unwrap_or(-1).to_be_bytes())?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Write the end record and return the underlying writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.write_all(&[TAG_END])?;
+        self.inner.write_all(&self.entries.to_be_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads the entries of a dump, failing if the dump is truncated or of an unknown version
+pub struct DumpReader<R: Read> {
+    inner: R,
+    entries: u64,
+    finished: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<DumpReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a knowsql dump"));
+        }
+
+        let mut version = [0; 1];
+        inner.read_exact(&mut version)?;
+        if version[0] != VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported dump version {}",
+                version[0]
+            )));
+        }
+
+        Ok(DumpReader {
+            inner,
+            entries: 0,
+            finished: false,
+        })
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        let mut buf = [0; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_bytes(&mut self, size: u32) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(size as u64).read_to_end(&mut buf)?;
+        if buf.len() != size as usize {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    fn read_entry(&mut self) -> std::io::Result<Option<DumpEntry>> {
+        let mut tag = [0; 1];
+        self.inner.read_exact(&mut tag)?;
+
+        match tag[0] {
+            TAG_ENTRY => {
+                let key_size = self.read_u32()?;
+                let key = String::from_utf8(self.read_bytes(key_size)?)
+                    .map_err(|_| invalid_data("key is not valid utf8"))?;
+                let value_size = self.read_u32()?;
+                let value = self.read_bytes(value_size)?;
+
+                let mut expire_at = [0; 8];
+                self.inner.read_exact(&mut expire_at)?;
+                let expire_at = match i64::from_be_bytes(expire_at) {
+                    -1 => None,
+                    expire_at => Some(expire_at),
+                };
+
+                self.entries += 1;
+                Ok(Some(DumpEntry {
+                    key,
+                    value,
+                    expire_at,
+                }))
+            }
+            TAG_END => {
+                let mut entries = [0; 8];
+                self.inner.read_exact(&mut entries)?;
+                if u64::from_be_bytes(entries) != self.entries {
+                    return Err(invalid_data("entry count does not match end record"));
+                }
+                Ok(None)
+            }
+            tag => Err(invalid_data(&format!("unknown record tag {:#04x}", tag))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = std::io::Result<DumpEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Write every key-value pair in the store as a dump, returning the number of entries written
+pub fn export<W: Write>(cask: &BitCask, writer: W) -> std::io::Result<u64> {
+    let mut writer = DumpWriter::new(writer)?;
+    for pair in cask.iter() {
+        let (key, value) = pair?;
+        writer.write_entry(key, &value, None)?;
+    }
+
+    let entries = writer.entries;
+    writer.finish()?;
+    Ok(entries)
+}
+
+/// Put every entry of a dump into the store, overwriting existing keys
+pub fn import<R: Read>(cask: &mut BitCask, reader: R) -> std::io::Result<ImportSummary> {
+    let now = Utc::now().timestamp_millis();
+    let mut summary = ImportSummary::default();
+
+    for entry in DumpReader::new(reader)? {
+        let entry = entry?;
+        match entry.expire_at {
+            Some(expire_at) if expire_at <= now => {
+                summary.expired += 1;
+                continue;
+            }
+            Some(_) => summary.expiry_dropped += 1,
+            None => (),
+        }
+
+        cask.put(&entry.key, &entry.value)?;
+        summary.imported += 1;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = DumpWriter::new(Vec::new()).unwrap();
+        writer.write_entry("hello", b"world", None).unwrap();
+        writer.write_entry("", b"", Some(1000)).unwrap();
+        let dump = writer.finish().unwrap();
+
+        let entries: Vec<_> = DumpReader::new(&dump[..])
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                DumpEntry {
+                    key: "hello".to_string(),
+                    value: b"world".to_vec(),
+                    expire_at: None
+                },
+                DumpEntry {
+                    key: "".to_string(),
+                    value: vec![],
+                    expire_at: Some(1000)
+                },
+            ]
+        );
+
+        let truncated = DumpReader::new(&dump[..dump.len() - 9])
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>();
+        assert!(truncated.is_err());
+    }
+}
@@ -1,4 +1,72 @@
-///! Entries represent the data that will be stored directly in the data file
+//! Entries represent the data that will be stored directly in the data file
+//!
+//! A data file is a file header followed by big endian entries;
+//!
+//! ```text
+//! header: "KNOWSQL-DATA" version:u8
+//! entry:  crc:u32 timestamp:i64 key_size:u32 value_size:u32 key value
+//! ```
+//!
+//! `crc` is the crc32 of the rest of the entry. Data files written before entries carried a
+//! checksum have no header and their entries start at `timestamp`.
+//!
+//! Two `value_size`s mark entries without a value; [`TOMBSTONE`] records that its key was
+//! deleted and [`CLEARED`], with an empty key, that every key written before it was deleted.
+
+use std::mem::size_of;
+
+pub const MAGIC: &[u8; 12] = b"KNOWSQL-DATA";
+pub const VERSION: u8 = 1;
+
+/// `value_size` of an entry recording that its key was deleted
+pub const TOMBSTONE: u32 = u32::MAX;
+/// `value_size` of an entry recording that every key written before it was deleted
+pub const CLEARED: u32 = u32::MAX - 1;
+
+/// Size of the magic and version starting every data file with checksums
+pub const FILE_HEADER_SIZE: u64 = MAGIC.len() as u64 + 1;
+
+/// Size of the fixed `timestamp`, `key_size` and `value_size` prefix of an entry
+const LEGACY_HEADER_SIZE: usize = size_of::<i64>() + size_of::<u32>() + size_of::<u32>();
+
+/// Layout of the entries within a data file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Written before entries carried a checksum, read but never appended to
+    Legacy,
+    /// Every entry starts with a crc32 of the rest of the entry
+    Checksummed,
+}
+
+impl Format {
+    /// Size of the fixed prefix of an entry, before the key
+    pub fn header_size(self) -> usize {
+        match self {
+            Format::Legacy => LEGACY_HEADER_SIZE,
+            Format::Checksummed => size_of::<u32>() + LEGACY_HEADER_SIZE,
+        }
+    }
+
+    /// Offset of the first entry of a data file
+    pub fn start(self) -> u64 {
+        match self {
+            Format::Legacy => 0,
+            Format::Checksummed => FILE_HEADER_SIZE,
+        }
+    }
+
+    /// Number of bytes an entry with the given key and value sizes occupies on disk
+    pub fn entry_size(self, key_size: u32, value_size: u32) -> u64 {
+        self.header_size() as u64 + key_size as u64 + value_size as u64
+    }
+}
+
+/// The file header written at the start of every new data file
+pub fn file_header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    buf
+}
 
 #[derive(Clone, Debug)]
 pub struct Entry<'a> {
@@ -9,14 +77,40 @@ pub struct Entry<'a> {
     pub value: &'a [u8],
 }
 
-impl Entry<'_> {
+impl<'a> Entry<'a> {
+    /// An entry recording that `key` was deleted
+    pub fn tombstone(timestamp: i64, key: &'a str) -> Entry<'a> {
+        Entry {
+            timestamp,
+            key_size: key.len() as u32,
+            value_size: TOMBSTONE,
+            key,
+            value: &[],
+        }
+    }
+
+    /// An entry recording that every key written before it was deleted
+    pub fn cleared(timestamp: i64) -> Entry<'a> {
+        Entry {
+            timestamp,
+            key_size: 0,
+            value_size: CLEARED,
+            key: "",
+            value: &[],
+        }
+    }
+
+    /// Serialize the entry in the [`Format::Checksummed`] layout
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+        let mut buf = vec![0; size_of::<u32>()];
         buf.extend_from_slice(&self.timestamp.to_be_bytes());
         buf.extend_from_slice(&self.key_size.to_be_bytes());
         buf.extend_from_slice(&self.value_size.to_be_bytes());
         buf.extend_from_slice(self.key.as_bytes());
         buf.extend_from_slice(self.value);
+
+        let crc = crc32fast::hash(&buf[size_of::<u32>()..]);
+        buf[..size_of::<u32>()].copy_from_slice(&crc.to_be_bytes());
         buf
     }
 }
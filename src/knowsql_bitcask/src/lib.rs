@@ -1,13 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
 use chrono::Utc;
+use fs2::FileExt;
 
+mod cache;
+pub mod dump;
 mod entry;
-use entry::Entry;
+pub mod segment;
+mod stats;
+pub mod verify;
+pub use cache::CacheStats;
+use cache::ReadCache;
+use entry::{file_header, Entry, Format, CLEARED, FILE_HEADER_SIZE};
+use segment::{Corruption, Kind, SegmentReader};
+pub use stats::{SegmentStats, Stats};
 
 #[derive(Debug)]
 pub struct BitCask {
@@ -16,60 +27,191 @@ pub struct BitCask {
     key_dir: HashMap<String, Key>,
 
     write_handle: File,
-    read_handle: File,
+    /// Every data file by id, the active one included
+    segments: BTreeMap<u32, Segment>,
+    /// Exclusively locked for as long as the store is open, so only one process writes to it
+    lock: File,
+
+    cache: Option<ReadCache>,
+
+    /// Bytes appended by [`BitCask::put()`], [`BitCask::delete()`] and [`BitCask::clear()`] since
+    /// the store was opened
+    bytes_written: u64,
+    /// Bytes rewritten by [`BitCask::merge()`] since the store was opened
+    bytes_merged: u64,
+    last_merge: Option<i64>,
+}
+
+/// A merge in progress, see [`BitCask::start_merge()`]
+#[derive(Debug)]
+pub struct Merge {
+    /// Id of the data file the live entries are copied to
+    file_id: u32,
+    /// Path the data file is written to until the merge completes
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: File,
+    /// Data files still to be copied, oldest first
+    pending: Vec<u32>,
+    /// Data files copied, removed once the merge completes
+    merged: Vec<u32>,
+    /// Keys moved to the merged file with their previous data file and value position, so
+    /// [`BitCask::abort_merge()`] can point them back
+    moved: Vec<(String, u32, u64)>,
+    position: u64,
+}
+
+/// Iterator over the live key-value pairs of a [`BitCask`], returned by [`BitCask::iter()`]
+pub struct Iter<'a> {
+    keys: hash_map::Iter<'a, String, Key>,
+    segments: &'a BTreeMap<u32, Segment>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = std::io::Result<(&'a str, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, meta) = self.keys.next()?;
+
+        let mut file = &self.segments[&meta.file_id].reader;
+        let mut value = vec![0; meta.value_size as usize];
+        let read = file
+            .seek(SeekFrom::Start(meta.value_position))
+            .and_then(|_| file.read_exact(&mut value));
+
+        Some(read.map(|_| (key.as_str(), value)))
+    }
+}
+
+/// A data file of the store
+#[derive(Debug)]
+struct Segment {
+    reader: File,
+    /// Size of the data file up to the end of its last readable entry
+    size: u64,
+    format: Format,
+    /// Whether the data file holds bytes past `size` that could not be read
+    corrupt: bool,
 }
 
 /// A key to locate a value within a data file
 #[derive(Debug)]
 struct Key {
-    #[allow(dead_code)]
     file_id: u32,
     value_size: u32,
     value_position: u64,
-    #[allow(dead_code)]
     timestamp: i64,
 }
 
-impl BitCask {
-    fn build_key_dir(&mut self) {
-        self.read_handle.rewind().unwrap();
+/// Path of the data file with the given id
+fn data_file(data_dir: &Path, file_id: u32) -> PathBuf {
+    data_dir.join(format!("{}.data", file_id))
+}
 
-        loop {
-            const HEADER_SIZE: usize = size_of::<i64>() + size_of::<u32>() + size_of::<u32>();
-            let mut buf = [0; HEADER_SIZE];
+/// Find the ids of every data file, oldest first, removing the unfinished output of an
+/// interrupted merge. The data files it was reading from are kept, so nothing is lost
+fn find_file_ids(data_dir: &Path) -> std::io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for dir_entry in std::fs::read_dir(data_dir)? {
+        let path = dir_entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
 
-            match self.read_handle.read_exact(&mut buf) {
-                Ok(_) => (),
-                Err(_) => break,
-            }
+        if name.ends_with(".data.merge") {
+            std::fs::remove_file(&path)?;
+        } else if let Some(id) = name.strip_suffix(".data").and_then(|id| id.parse().ok()) {
+            ids.push(id);
+        }
+    }
+
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Lock the data directory, failing if another process has it open
+fn lock_data_dir(data_dir: &Path) -> std::io::Result<File> {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(data_dir.join("LOCK"))?;
+
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Err(std::io::Error::new(
+            ErrorKind::WouldBlock,
+            format!("{} is in use by another process", data_dir.display()),
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// Load every entry of a data file into the key dir, stopping at the first corrupt entry.
+/// Data files must be loaded oldest first so newer entries replace older ones.
+/// [`verify::verify()`] reports and repairs such corruption
+fn load_segment(
+    data_dir: &Path,
+    file_id: u32,
+    key_dir: &mut HashMap<String, Key>,
+) -> std::io::Result<Segment> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(data_file(data_dir, file_id))?;
+    let mut reader = SegmentReader::new(&file)?;
 
-            let timestamp = i64::from_be_bytes(buf[..8].try_into().unwrap());
-            let key_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
-            let value_size = u32::from_be_bytes(buf[12..16].try_into().unwrap());
-            let key = &mut vec![0; key_size as usize];
-            self.read_handle.read_exact(key).unwrap();
-
-            // Skip over value
-            let pos = self
-                .read_handle
-                .stream_position()
-                .expect("we just read from the file");
-            self.read_handle
-                .seek(std::io::SeekFrom::Current(value_size as i64))
-                .expect("content is not malformed");
-
-            self.key_dir.insert(
-                String::from_utf8(key.to_vec()).unwrap(),
-                Key {
-                    file_id: self.active_file_id,
-                    value_size: value_size,
-                    value_position: pos,
-                    timestamp: timestamp,
-                },
-            );
+    for record in reader.by_ref() {
+        let record = match record {
+            Ok(record) => record,
+            Err(Corruption::Io(err)) => return Err(err),
+            Err(_) => break,
+        };
+
+        match record.kind {
+            Kind::Value => {
+                key_dir.insert(
+                    record.key,
+                    Key {
+                        file_id,
+                        value_size: record.value_size,
+                        value_position: record.value_position,
+                        timestamp: record.timestamp,
+                    },
+                );
+            }
+            Kind::Tombstone => {
+                key_dir.remove(&record.key);
+            }
+            Kind::Cleared => key_dir.clear(),
         }
     }
 
+    Ok(Segment {
+        size: reader.offset(),
+        format: reader.format(),
+        corrupt: reader.offset() < reader.len(),
+        reader: file,
+    })
+}
+
+/// Create an empty data file, starting with the file header
+fn create_segment(data_dir: &Path, file_id: u32) -> std::io::Result<Segment> {
+    let path = data_file(data_dir, file_id);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?
+        .write_all(&file_header())?;
+
+    Ok(Segment {
+        reader: OpenOptions::new().read(true).open(&path)?,
+        size: FILE_HEADER_SIZE,
+        format: Format::Checksummed,
+        corrupt: false,
+    })
+}
+
+impl BitCask {
     /// Open a BitCask store
     ///   if provided data_dir does not exist it will be created or an error will be returned
     pub fn open(data_dir: PathBuf) -> std::io::Result<BitCask> {
@@ -77,57 +219,132 @@ impl BitCask {
             std::fs::create_dir(&data_dir)?;
         }
 
-        let active_file = data_dir.join("0.data");
+        let lock = lock_data_dir(&data_dir)?;
+        BitCask::open_locked(data_dir, lock)
+    }
+    /// Open a BitCask store whose data directory the caller already locked
+    fn open_locked(data_dir: PathBuf, lock: File) -> std::io::Result<BitCask> {
+        let mut key_dir = HashMap::new();
+        let mut segments = BTreeMap::new();
+        for file_id in find_file_ids(&data_dir)? {
+            segments.insert(file_id, load_segment(&data_dir, file_id, &mut key_dir)?);
+        }
+
+        // Entries are only appended to a data file with checksums, older data files are
+        // rewritten by the next merge. Nor are they appended after a corrupt tail, which would
+        // hide them from the next open, the tail is left for verify to report
+        let active_file_id = match segments.last_key_value() {
+            Some((&file_id, segment))
+                if segment.format == Format::Checksummed && !segment.corrupt =>
+            {
+                file_id
+            }
+            Some((&file_id, _)) => file_id + 1,
+            None => 0,
+        };
+        if let btree_map::Entry::Vacant(vacant) = segments.entry(active_file_id) {
+            vacant.insert(create_segment(&data_dir, active_file_id)?);
+        }
         let write_handle = OpenOptions::new()
             .append(true)
-            .create(true)
-            .open(&active_file)?;
-        let read_handle = OpenOptions::new().read(true).open(&active_file)?;
+            .open(data_file(&data_dir, active_file_id))?;
 
-        let mut cask = BitCask {
+        Ok(BitCask {
             data_dir,
-            active_file_id: 0,
-            key_dir: HashMap::new(),
+            active_file_id,
+            key_dir,
             write_handle,
-            read_handle,
+            segments,
+            lock,
+            cache: None,
+            bytes_written: 0,
+            bytes_merged: 0,
+            last_merge: None,
+        })
+    }
+    /// Flush written entries to disk
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.write_handle.sync_all()
+    }
+    /// Release the lock on the data directory so another process may open it
+    ///   the store must not be written to afterwards, the lock is also released on drop
+    pub fn close(&mut self) -> std::io::Result<()> {
+        FileExt::unlock(&self.lock)
+    }
+    /// Place a LRU cache holding up to `capacity` values in front of reads
+    ///   a capacity of 0 disables the cache
+    pub fn with_read_cache(mut self, capacity: usize) -> BitCask {
+        self.cache = NonZeroUsize::new(capacity).map(ReadCache::new);
+        self
+    }
+    /// Hit and miss counters of the read cache, if enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(ReadCache::stats)
+    }
+    /// Get a value from the store
+    ///   values that are not valid utf8 fail with [`ErrorKind::InvalidData`]
+    pub fn get(&mut self, key: &str) -> std::io::Result<Option<String>> {
+        let Some(meta) = self.key_dir.get(key) else {
+            return Ok(None);
         };
 
-        cask.build_key_dir();
+        if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some(value));
+        }
+
+        let mut file = &self.segments[&meta.file_id].reader;
+        file.seek(SeekFrom::Start(meta.value_position))?;
+
+        let mut buf = vec![0; meta.value_size as usize];
+        file.read_exact(&mut buf)?;
+        let value = String::from_utf8(buf)
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "value is not valid utf8"))?;
 
-        Ok(cask)
+        if let Some(cache) = self.cache.as_mut() {
+            cache.insert(key, value.clone());
+        }
+
+        Ok(Some(value))
     }
-    /// Get a value from the store
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        let meta = self.key_dir.get(key)?;
+    /// Append an entry to the active data file, returning the offset just past it
+    fn append(&mut self, entry: &Entry) -> std::io::Result<u64> {
+        let e = entry.serialize();
 
-        self.read_handle
-            .seek(std::io::SeekFrom::Start(meta.value_position))
-            .unwrap();
+        self.write_handle.write_all(&e)?;
+        self.write_handle.flush()?;
+        let p = self.write_handle.stream_position()?;
+        if let Some(active) = self.segments.get_mut(&self.active_file_id) {
+            active.size = p;
+        }
+        self.bytes_written += e.len() as u64;
 
-        let mut buf = vec![0; meta.value_size as usize];
-        self.read_handle.read_exact(&mut buf).unwrap();
-        Some(String::from_utf8(buf).unwrap())
+        Ok(p)
     }
     /// Put a key-value pair into the store
     pub fn put(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        // Larger sizes mark tombstones and cleared markers
+        let value_size = u32::try_from(value.len())
+            .ok()
+            .filter(|&size| size < CLEARED)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "value too large"))?;
         let entry = Entry {
             timestamp: Utc::now().timestamp(),
             key_size: key.len() as u32,
-            value_size: value.len() as u32,
-            key: key,
-            value: value,
+            value_size,
+            key,
+            value,
         };
 
-        let e = entry.serialize();
+        let p = self.append(&entry)?;
 
-        self.write_handle.write(&e)?;
-        self.write_handle.flush()?;
-        let p = self.write_handle.stream_position().unwrap();
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(key);
+        }
 
         self.key_dir.insert(
             key.to_string(),
             Key {
-                file_id: 0,
+                file_id: self.active_file_id,
                 value_size: value.len() as u32,
                 value_position: p - entry.value_size as u64,
                 timestamp: entry.timestamp,
@@ -136,9 +353,33 @@ impl BitCask {
 
         Ok(())
     }
-    /// Delete a key from the store
-    pub fn delete(&mut self, key: &str) -> Option<()> {
-        self.key_dir.remove(key).map(|_| ())
+    /// Delete a key from the store, returning whether it held a value
+    ///   a tombstone is appended so the key stays deleted once the store is reopened
+    pub fn delete(&mut self, key: &str) -> std::io::Result<bool> {
+        if !self.key_dir.contains_key(key) {
+            return Ok(false);
+        }
+
+        self.append(&Entry::tombstone(Utc::now().timestamp(), key))?;
+
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(key);
+        }
+
+        Ok(self.key_dir.remove(key).is_some())
+    }
+    /// Delete every key from the store
+    ///   a marker is appended so the keys stay deleted once the store is reopened
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        self.append(&Entry::cleared(Utc::now().timestamp()))?;
+
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+
+        self.key_dir.clear();
+
+        Ok(())
     }
     /// Alias for [`BitCask::list_keys()`]
     pub fn keys(&self) -> Vec<String> {
@@ -148,4 +389,486 @@ impl BitCask {
     pub fn list_keys(&self) -> Vec<String> {
         self.key_dir.keys().cloned().collect()
     }
+    /// Iterate all keys in the store without copying them, in no particular order
+    pub fn iter_keys(&self) -> impl ExactSizeIterator<Item = &str> {
+        self.key_dir.keys().map(String::as_str)
+    }
+    /// Whether the store holds a value for `key`
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.key_dir.contains_key(key)
+    }
+    /// Number of keys in the store
+    pub fn len(&self) -> usize {
+        self.key_dir.len()
+    }
+    /// Whether the store holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.key_dir.is_empty()
+    }
+    /// Iterate all key-value pairs in the store, in no particular order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            keys: self.key_dir.iter(),
+            segments: &self.segments,
+        }
+    }
+    /// Live statistics of the store
+    pub fn stats(&self) -> Stats {
+        let mut live_bytes: HashMap<u32, u64> = HashMap::new();
+        for (key, meta) in &self.key_dir {
+            let format = self.segments[&meta.file_id].format;
+            *live_bytes.entry(meta.file_id).or_default() +=
+                format.entry_size(key.len() as u32, meta.value_size);
+        }
+
+        let key_dir_bytes = self.key_dir.capacity() * size_of::<(String, Key)>()
+            + self.key_dir.keys().map(String::capacity).sum::<usize>();
+
+        let write_amplification = match self.bytes_written {
+            0 => 0.0,
+            written => (written + self.bytes_merged) as f64 / written as f64,
+        };
+
+        Stats {
+            keys: self.key_dir.len(),
+            segments: self
+                .segments
+                .iter()
+                .map(|(&file_id, segment)| {
+                    let live_bytes = live_bytes.get(&file_id).copied().unwrap_or_default();
+                    SegmentStats {
+                        file_id,
+                        total_bytes: segment.size,
+                        live_bytes,
+                        dead_bytes: segment
+                            .size
+                            .saturating_sub(segment.format.start() + live_bytes),
+                    }
+                })
+                .collect(),
+            key_dir_bytes,
+            last_merge: self.last_merge,
+            write_amplification,
+        }
+    }
+    /// Rewrite the live entries of every data file into a new one, reclaiming the space of
+    /// overwritten and deleted entries. The new file only replaces the old ones once fully
+    /// written.
+    pub fn merge(&mut self) -> std::io::Result<()> {
+        let mut merge = self.start_merge()?;
+        loop {
+            match self.merge_segment(&mut merge) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => {
+                    self.abort_merge(merge)?;
+                    return Err(err);
+                }
+            }
+        }
+        self.finish_merge(merge)
+    }
+    /// Start a merge of every data file, moving writes to a new data file so the merged ones
+    /// no longer change. The merge is carried out a data file at a time by
+    /// [`BitCask::merge_segment()`], so a caller sharing the store can release it in between
+    pub fn start_merge(&mut self) -> std::io::Result<Merge> {
+        let file_id = self.active_file_id + 1;
+        let active_file_id = file_id + 1;
+        let active = create_segment(&self.data_dir, active_file_id)?;
+        self.write_handle = OpenOptions::new()
+            .append(true)
+            .open(data_file(&self.data_dir, active_file_id))?;
+        self.segments.insert(active_file_id, active);
+        self.active_file_id = active_file_id;
+
+        let path = self.data_dir.join(format!("{}.data.merge", file_id));
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)?,
+        );
+        writer.write_all(&file_header())?;
+
+        Ok(Merge {
+            file_id,
+            reader: OpenOptions::new().read(true).open(&path)?,
+            path,
+            writer,
+            pending: self.segments.range(..file_id).map(|(id, _)| *id).collect(),
+            merged: Vec::new(),
+            moved: Vec::new(),
+            position: FILE_HEADER_SIZE,
+        })
+    }
+    /// Copy the live entries of the next data file of a merge, returning false once every data
+    /// file has been copied. Deleted keys are no longer in the key dir, so neither their values
+    /// nor their tombstones are copied; every data file they could hide a value in is merged
+    pub fn merge_segment(&mut self, merge: &mut Merge) -> std::io::Result<bool> {
+        if merge.pending.is_empty() {
+            return Ok(false);
+        }
+        let source_id = merge.pending.remove(0);
+
+        // Copy values in file order to keep reads sequential
+        let mut live: Vec<_> = self
+            .key_dir
+            .iter_mut()
+            .filter(|(_, meta)| meta.file_id == source_id)
+            .collect();
+        live.sort_by_key(|(_, meta)| meta.value_position);
+
+        let mut file = &self.segments[&source_id].reader;
+        for (key, meta) in live {
+            let mut value = vec![0; meta.value_size as usize];
+            file.seek(SeekFrom::Start(meta.value_position))?;
+            file.read_exact(&mut value)?;
+
+            let entry = Entry {
+                timestamp: meta.timestamp,
+                key_size: key.len() as u32,
+                value_size: meta.value_size,
+                key,
+                value: &value,
+            };
+            merge.writer.write_all(&entry.serialize())?;
+            merge.position += Format::Checksummed.entry_size(entry.key_size, entry.value_size);
+
+            merge
+                .moved
+                .push((key.clone(), meta.file_id, meta.value_position));
+            meta.file_id = merge.file_id;
+            meta.value_position = merge.position - meta.value_size as u64;
+        }
+
+        // The key dir now points into the merged file, so it must be readable
+        merge.writer.flush()?;
+        let merged = Segment {
+            reader: merge.reader.try_clone()?,
+            size: merge.position,
+            format: Format::Checksummed,
+            corrupt: false,
+        };
+        self.segments.insert(merge.file_id, merged);
+        merge.merged.push(source_id);
+
+        Ok(true)
+    }
+    /// Give up on a merge that failed part way, pointing the keys it moved back at the data
+    /// files they were copied from and removing the partially written file
+    pub fn abort_merge(&mut self, merge: Merge) -> std::io::Result<()> {
+        for (key, file_id, value_position) in merge.moved {
+            // Keys written or deleted since they were moved no longer point into the merge
+            if let Some(meta) = self.key_dir.get_mut(&key) {
+                if meta.file_id == merge.file_id {
+                    meta.file_id = file_id;
+                    meta.value_position = value_position;
+                }
+            }
+        }
+
+        self.segments.remove(&merge.file_id);
+        drop(merge.writer);
+        std::fs::remove_file(&merge.path)
+    }
+    /// Complete a merge once every data file was copied, replacing the merged data files
+    pub fn finish_merge(&mut self, merge: Merge) -> std::io::Result<()> {
+        merge.writer.into_inner()?.sync_all()?;
+        std::fs::rename(&merge.path, data_file(&self.data_dir, merge.file_id))?;
+
+        for file_id in merge.merged {
+            self.segments.remove(&file_id);
+            std::fs::remove_file(data_file(&self.data_dir, file_id))?;
+        }
+
+        self.segments.entry(merge.file_id).or_insert(Segment {
+            reader: merge.reader,
+            size: merge.position,
+            format: Format::Checksummed,
+            corrupt: false,
+        });
+        self.bytes_merged += merge.position;
+        self.last_merge = Some(Utc::now().timestamp());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn temp_data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("knowsql-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_merge_reclaims_dead_bytes() {
+        let dir = temp_data_dir("merge");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.put("a", b"2").unwrap();
+        cask.put("b", b"3").unwrap();
+        assert!(cask.delete("b").unwrap());
+
+        let stats = cask.stats();
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.live_bytes(), Format::Checksummed.entry_size(1, 1));
+        // The overwritten `a`, the deleted `b` and its tombstone
+        assert_eq!(
+            stats.dead_bytes(),
+            2 * Format::Checksummed.entry_size(1, 1) + Format::Checksummed.entry_size(1, 0)
+        );
+
+        cask.merge().unwrap();
+        let stats = cask.stats();
+        assert_eq!(stats.dead_bytes(), 0);
+        // The merged data file and the new active one
+        assert_eq!(
+            stats.total_bytes(),
+            2 * FILE_HEADER_SIZE + Format::Checksummed.entry_size(1, 1)
+        );
+        assert!(stats.last_merge.is_some());
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+
+        drop(cask);
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+        assert_eq!(cask.get("b").unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_after_interrupted_merge() {
+        let dir = temp_data_dir("interrupted");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.put("b", b"1").unwrap();
+        drop(cask);
+
+        // A merge that was cut short leaves its unfinished output next to the data files it
+        // read from, and possibly a newer data file it already wrote
+        std::fs::write(dir.join("2.data.merge"), b"unfinished").unwrap();
+        let other = temp_data_dir("interrupted-other");
+        let mut cask = BitCask::open(other.clone()).unwrap();
+        cask.put("b", b"2").unwrap();
+        cask.put("c", b"2").unwrap();
+        drop(cask);
+        std::fs::copy(other.join("0.data"), dir.join("1.data")).unwrap();
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert!(!dir.join("2.data.merge").exists());
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("2"));
+
+        let stats = cask.stats();
+        assert_eq!(stats.segments.len(), 2);
+        assert_eq!(
+            stats.segments[0].live_bytes,
+            Format::Checksummed.entry_size(1, 1)
+        );
+        assert_eq!(
+            stats.segments[0].dead_bytes,
+            Format::Checksummed.entry_size(1, 1)
+        );
+        assert_eq!(stats.segments[1].dead_bytes, 0);
+
+        cask.merge().unwrap();
+        assert_eq!(cask.stats().segments.len(), 2);
+        assert!(!dir.join("0.data").exists());
+        assert!(!dir.join("1.data").exists());
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other).unwrap();
+    }
+
+    #[test]
+    fn test_writes_during_merge() {
+        let dir = temp_data_dir("merge-steps");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.put("b", b"1").unwrap();
+        cask.put("c", b"1").unwrap();
+
+        let mut merge = cask.start_merge().unwrap();
+        cask.put("a", b"2").unwrap();
+        cask.delete("b").unwrap();
+        assert!(cask.merge_segment(&mut merge).unwrap());
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("1"));
+        cask.put("c", b"2").unwrap();
+        assert!(!cask.merge_segment(&mut merge).unwrap());
+        cask.finish_merge(merge).unwrap();
+
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+        assert_eq!(cask.get("b").unwrap(), None);
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("2"));
+        // `c` was copied before it was overwritten, the tombstone of `b` was written after the
+        // merge started
+        assert_eq!(
+            cask.stats().dead_bytes(),
+            Format::Checksummed.entry_size(1, 1) + Format::Checksummed.entry_size(1, 0)
+        );
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+        assert_eq!(cask.get("b").unwrap(), None);
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_deletes_survive_reopen() {
+        let dir = temp_data_dir("deletes");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.put("b", b"2").unwrap();
+        assert!(cask.delete("a").unwrap());
+        assert!(!cask.delete("a").unwrap());
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap(), None);
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("2"));
+        cask.clear().unwrap();
+        cask.put("c", b"3").unwrap();
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.keys(), vec!["c".to_string()]);
+        cask.merge().unwrap();
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.keys(), vec!["c".to_string()]);
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("3"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_writes_after_corrupt_tail() {
+        let dir = temp_data_dir("corrupt-tail");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.put("b", b"2").unwrap();
+        drop(cask);
+
+        // A write torn part way through `b`
+        let path = dir.join("0.data");
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap(), None);
+        cask.put("c", b"3").unwrap();
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("c").unwrap().as_deref(), Some("3"));
+        // The corrupt tail is left in place for verify
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len - 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_abort_merge() {
+        let dir = temp_data_dir("merge-abort");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        cask.merge().unwrap();
+        cask.put("b", b"2").unwrap();
+
+        // `a` is in 1.data and `b` in 2.data, emptying 2.data fails the merge part way
+        let path = dir.join("2.data");
+        let contents = std::fs::read(&path).unwrap();
+        let mut merge = cask.start_merge().unwrap();
+        assert!(cask.merge_segment(&mut merge).unwrap());
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(FILE_HEADER_SIZE)
+            .unwrap();
+        cask.merge_segment(&mut merge).unwrap_err();
+        cask.abort_merge(merge).unwrap();
+        std::fs::write(&path, contents).unwrap();
+
+        assert!(!dir.join("3.data.merge").exists());
+        let file_ids: Vec<_> = cask.stats().segments.iter().map(|s| s.file_id).collect();
+        assert_eq!(file_ids, vec![1, 2, 4]);
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("2"));
+        drop(cask);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap().as_deref(), Some("2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_invalid_utf8() {
+        let dir = temp_data_dir("invalid-utf8");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", &[0xff, 0xfe]).unwrap();
+        let err = cask.get("a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let pairs: Vec<_> = cask.iter().map(Result::unwrap).collect();
+        assert_eq!(pairs, vec![("a", vec![0xff, 0xfe])]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_cache_invalidation() {
+        let dir = temp_data_dir("cache");
+        let mut cask = BitCask::open(dir.clone()).unwrap().with_read_cache(2);
+        cask.put("a", b"1").unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.cache_stats().unwrap().hits, 1);
+
+        cask.put("a", b"2").unwrap();
+        assert_eq!(cask.cache_stats().unwrap().len, 0);
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("2"));
+
+        cask.delete("a").unwrap();
+        assert_eq!(cask.cache_stats().unwrap().len, 0);
+        assert_eq!(cask.get("a").unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_data_dir_lock() {
+        let dir = temp_data_dir("lock");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        let err = BitCask::open(dir.clone()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        cask.close().unwrap();
+        drop(BitCask::open(dir.clone()).unwrap());
+        drop(cask);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,220 @@
+//! Sequential reading of the entries stored in a data file
+
+use std::fmt;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::entry::{Format, CLEARED, FILE_HEADER_SIZE, MAGIC, TOMBSTONE, VERSION};
+
+/// What an entry records about its key
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    /// A value was written
+    Value,
+    /// The key was deleted
+    Tombstone,
+    /// Every key written before the entry was deleted, the key is empty
+    Cleared,
+}
+
+/// Location and metadata of an entry within a data file, the value itself is not read
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Offset of the start of the entry
+    pub offset: u64,
+    pub timestamp: i64,
+    pub kind: Kind,
+    pub key: String,
+    /// Size of the value, 0 for tombstones and cleared markers
+    pub value_size: u32,
+    pub value_position: u64,
+    format: Format,
+}
+
+impl Record {
+    /// Number of bytes the entry occupies on disk
+    pub fn size(&self) -> u64 {
+        self.format
+            .entry_size(self.key.len() as u32, self.value_size)
+    }
+}
+
+/// Reason a data file could not be read past a given offset
+#[derive(Debug)]
+pub enum Corruption {
+    /// The file ends part way through an entry header
+    TruncatedHeader,
+    /// The key size points past the end of the file
+    TruncatedKey,
+    /// The value size points past the end of the file
+    TruncatedValue,
+    /// The key is not a valid utf8 string
+    InvalidKey,
+    /// The timestamp is negative
+    InvalidTimestamp,
+    /// The entry does not match its checksum
+    ChecksumMismatch,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::TruncatedHeader => write!(f, "truncated entry header"),
+            Corruption::TruncatedKey => write!(f, "key extends past end of file"),
+            Corruption::TruncatedValue => write!(f, "value extends past end of file"),
+            Corruption::InvalidKey => write!(f, "key is not valid utf8"),
+            Corruption::InvalidTimestamp => write!(f, "timestamp is negative"),
+            Corruption::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Corruption::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+/// Iterate the entries of a data file, stopping at the end of the file or the first corrupt entry
+pub struct SegmentReader<R> {
+    inner: R,
+    format: Format,
+    offset: u64,
+    len: u64,
+    failed: bool,
+}
+
+impl<R: Read + Seek> SegmentReader<R> {
+    /// Start reading a data file, files without the file header are read as [`Format::Legacy`]
+    pub fn new(mut inner: R) -> std::io::Result<SegmentReader<R>> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        inner.rewind()?;
+
+        let mut header = [0; FILE_HEADER_SIZE as usize];
+        if len >= FILE_HEADER_SIZE {
+            inner.read_exact(&mut header)?;
+        }
+
+        let format = if header.starts_with(MAGIC) {
+            if header[MAGIC.len()] != VERSION {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported data file version {}", header[MAGIC.len()]),
+                ));
+            }
+            Format::Checksummed
+        } else {
+            inner.rewind()?;
+            Format::Legacy
+        };
+
+        Ok(SegmentReader {
+            inner,
+            format,
+            offset: format.start(),
+            len,
+            failed: false,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Offset just past the last entry that was read successfully
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the data file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn read_record(&mut self) -> Result<Record, Corruption> {
+        let remaining = self.len - self.offset;
+        let header_size = self.format.header_size();
+        if remaining < header_size as u64 {
+            return Err(Corruption::TruncatedHeader);
+        }
+
+        let mut header = vec![0; header_size];
+        self.inner.read_exact(&mut header).map_err(Corruption::Io)?;
+        let (crc, fields) = match self.format {
+            Format::Legacy => (None, &header[..]),
+            Format::Checksummed => (
+                Some(u32::from_be_bytes(header[..4].try_into().unwrap())),
+                &header[4..],
+            ),
+        };
+        let timestamp = i64::from_be_bytes(fields[..8].try_into().unwrap());
+        let key_size = u32::from_be_bytes(fields[8..12].try_into().unwrap());
+        let (kind, value_size) = match u32::from_be_bytes(fields[12..16].try_into().unwrap()) {
+            TOMBSTONE if self.format == Format::Checksummed => (Kind::Tombstone, 0),
+            CLEARED if self.format == Format::Checksummed => (Kind::Cleared, 0),
+            value_size => (Kind::Value, value_size),
+        };
+
+        if timestamp < 0 {
+            return Err(Corruption::InvalidTimestamp);
+        }
+        if remaining < header_size as u64 + key_size as u64 {
+            return Err(Corruption::TruncatedKey);
+        }
+        if remaining < self.format.entry_size(key_size, value_size) {
+            return Err(Corruption::TruncatedValue);
+        }
+
+        let mut key = vec![0; key_size as usize];
+        self.inner.read_exact(&mut key).map_err(Corruption::Io)?;
+
+        let value_position = self.offset + header_size as u64 + key_size as u64;
+        if let Some(crc) = crc {
+            let mut value = vec![0; value_size as usize];
+            self.inner.read_exact(&mut value).map_err(Corruption::Io)?;
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(fields);
+            hasher.update(&key);
+            hasher.update(&value);
+            if hasher.finalize() != crc {
+                return Err(Corruption::ChecksumMismatch);
+            }
+        }
+
+        let key = String::from_utf8(key).map_err(|_| Corruption::InvalidKey)?;
+        self.inner
+            .seek(SeekFrom::Start(value_position + value_size as u64))
+            .map_err(Corruption::Io)?;
+
+        Ok(Record {
+            offset: self.offset,
+            timestamp,
+            kind,
+            key,
+            value_size,
+            value_position,
+            format: self.format,
+        })
+    }
+}
+
+impl<R: Read + Seek> Iterator for SegmentReader<R> {
+    type Item = Result<Record, Corruption>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset == self.len {
+            return None;
+        }
+
+        match self.read_record() {
+            Ok(record) => {
+                self.offset += record.size();
+                Some(Ok(record))
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Statistics describing the state of the store on disk and in memory
+
+/// Byte usage of a single data file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    pub file_id: u32,
+    /// Size of the data file
+    pub total_bytes: u64,
+    /// Bytes taken by entries that are still referenced by the key dir
+    pub live_bytes: u64,
+    /// Bytes taken by overwritten or deleted entries, reclaimable by a merge
+    pub dead_bytes: u64,
+}
+
+/// Snapshot of the store, returned by [`crate::BitCask::stats()`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Number of live keys
+    pub keys: usize,
+    pub segments: Vec<SegmentStats>,
+    /// Estimate of the memory held by the key dir
+    pub key_dir_bytes: usize,
+    /// Unix timestamp of the last completed merge
+    pub last_merge: Option<i64>,
+    /// Bytes written to disk, including merges, per byte written by callers
+    pub write_amplification: f64,
+}
+
+impl Stats {
+    pub fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.total_bytes).sum()
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.live_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.dead_bytes).sum()
+    }
+
+    /// Fraction of the data on disk that a merge would reclaim
+    pub fn dead_ratio(&self) -> f64 {
+        match self.total_bytes() {
+            0 => 0.0,
+            total => self.dead_bytes() as f64 / total as f64,
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Offline integrity checks of a data directory
+//!
+//! Every entry must have a complete header, a utf8 key, a non-negative timestamp, a value ending
+//! within the file and match its checksum. Data files written before entries carried a checksum
+//! are only checked structurally.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::entry::Format;
+use crate::segment::{Corruption, Kind, SegmentReader};
+use crate::{lock_data_dir, BitCask};
+
+/// Bytes from the first unreadable entry to the end of the data file
+#[derive(Debug)]
+pub struct CorruptRange {
+    pub start: u64,
+    pub end: u64,
+    pub reason: Corruption,
+}
+
+#[derive(Debug)]
+pub struct SegmentReport {
+    pub file_id: u32,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Whether this is the data file the store appends to
+    pub active: bool,
+    /// Whether the entries carry checksums
+    pub checksums: bool,
+    pub records: u64,
+    /// Records superseded by a later record for the same key in any data file, or deleted by a
+    /// later clear
+    pub orphaned_records: u64,
+    pub orphaned_bytes: u64,
+    pub corrupt: Option<CorruptRange>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub segments: Vec<SegmentReport>,
+    /// Files left behind by an interrupted merge, removed when the store is next opened
+    pub stray_files: Vec<PathBuf>,
+}
+
+impl Report {
+    /// No segment contains corrupt entries
+    pub fn is_clean(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| segment.corrupt.is_none())
+    }
+}
+
+/// How [`repair()`] should fix corrupt segments
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Repair {
+    /// Cut every corrupt segment at the start of its corrupt range
+    Truncate,
+    /// Truncate, then rewrite the store keeping only the live entries
+    Rewrite,
+}
+
+/// Latest record of every key, with the index of the report of its data file and its size
+type Latest = HashMap<String, (usize, u64)>;
+
+fn orphan(segments: &mut [SegmentReport], (index, size): (usize, u64)) {
+    segments[index].orphaned_records += 1;
+    segments[index].orphaned_bytes += size;
+}
+
+/// Check a data file, counting the records of it and the data files before it that its own
+/// records supersede. Data files must be verified oldest first
+fn verify_segment(
+    file_id: u32,
+    path: PathBuf,
+    active: bool,
+    segments: &mut Vec<SegmentReport>,
+    latest: &mut Latest,
+) -> std::io::Result<()> {
+    let mut reader = SegmentReader::new(File::open(&path)?)?;
+    let index = segments.len();
+    segments.push(SegmentReport {
+        file_id,
+        path,
+        size: reader.len(),
+        active,
+        checksums: reader.format() == Format::Checksummed,
+        records: 0,
+        orphaned_records: 0,
+        orphaned_bytes: 0,
+        corrupt: None,
+    });
+
+    for record in reader.by_ref() {
+        match record {
+            Ok(record) if record.kind == Kind::Cleared => {
+                segments[index].records += 1;
+                for (_, superseded) in latest.drain() {
+                    orphan(segments, superseded);
+                }
+            }
+            Ok(record) => {
+                segments[index].records += 1;
+                let size = record.size();
+                if let Some(superseded) = latest.insert(record.key, (index, size)) {
+                    orphan(segments, superseded);
+                }
+            }
+            Err(reason) => {
+                segments[index].corrupt = Some(CorruptRange {
+                    start: 0,
+                    end: segments[index].size,
+                    reason,
+                })
+            }
+        }
+    }
+
+    if let Some(corrupt) = segments[index].corrupt.as_mut() {
+        corrupt.start = reader.offset();
+    }
+
+    Ok(())
+}
+
+/// Walk every data file within `data_dir` without opening the store
+pub fn verify(data_dir: &Path) -> std::io::Result<Report> {
+    let mut report = Report::default();
+    let mut segments = Vec::new();
+
+    for dir_entry in std::fs::read_dir(data_dir)? {
+        let path = dir_entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if name.ends_with(".data.merge") {
+            report.stray_files.push(path);
+        } else if let Some(id) = name.strip_suffix(".data").and_then(|id| id.parse().ok()) {
+            segments.push((id, path));
+        }
+    }
+
+    segments.sort();
+    let active = segments.last().map(|(id, _)| *id);
+
+    let mut latest = Latest::new();
+    for (file_id, path) in segments {
+        let active = Some(file_id) == active;
+        verify_segment(file_id, path, active, &mut report.segments, &mut latest)?;
+    }
+
+    Ok(report)
+}
+
+fn truncate(report: &Report) -> std::io::Result<()> {
+    for segment in &report.segments {
+        if let Some(corrupt) = &segment.corrupt {
+            OpenOptions::new()
+                .write(true)
+                .open(&segment.path)?
+                .set_len(corrupt.start)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fix the corrupt segments found by [`verify()`], failing if another process has the data
+/// directory open
+pub fn repair(data_dir: &Path, report: &Report, repair: Repair) -> std::io::Result<()> {
+    match repair {
+        Repair::Truncate => {
+            let _lock = lock_data_dir(data_dir)?;
+            truncate(report)
+        }
+        Repair::Rewrite => {
+            // The store is opened under the same lock, so nothing writes between the two
+            let lock = lock_data_dir(data_dir)?;
+            truncate(report)?;
+            BitCask::open_locked(data_dir.to_path_buf(), lock)?.merge()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_data_dir;
+    use std::io::ErrorKind;
+
+    /// A store whose only data file holds `a` then `b`, returning the offset of `b`
+    fn two_entries(name: &str) -> (PathBuf, u64) {
+        let dir = temp_data_dir(name);
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.put("a", b"1").unwrap();
+        let offset = cask.stats().total_bytes();
+        cask.put("b", b"2").unwrap();
+        (dir, offset)
+    }
+
+    fn corrupt_range(dir: &Path) -> (u64, u64, String) {
+        let report = verify(dir).unwrap();
+        assert!(!report.is_clean());
+        let corrupt = report.segments[0].corrupt.as_ref().unwrap();
+        (corrupt.start, corrupt.end, corrupt.reason.to_string())
+    }
+
+    #[test]
+    fn test_detects_corruption() {
+        let (dir, offset) = two_entries("verify-corrupt");
+        let path = dir.join("0.data");
+        let report = verify(&dir).unwrap();
+        assert!(report.is_clean());
+        assert!(report.segments[0].checksums);
+        assert_eq!(report.segments[0].records, 2);
+
+        // Flip the last byte of the value of `b`
+        let mut data = std::fs::read(&path).unwrap();
+        let size = data.len() as u64;
+        *data.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(
+            corrupt_range(&dir),
+            (offset, size, "checksum mismatch".to_string())
+        );
+
+        // Cut `b` short
+        data.pop();
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(
+            corrupt_range(&dir),
+            (
+                offset,
+                size - 1,
+                "value extends past end of file".to_string()
+            )
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair() {
+        let (dir, offset) = two_entries("verify-repair");
+        let mut data = std::fs::read(dir.join("0.data")).unwrap();
+        *data.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(dir.join("0.data"), &data).unwrap();
+        let report = verify(&dir).unwrap();
+
+        let cask = BitCask::open(dir.clone()).unwrap();
+        let err = repair(&dir, &report, Repair::Truncate).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        drop(cask);
+
+        repair(&dir, &report, Repair::Truncate).unwrap();
+        assert!(verify(&dir).unwrap().is_clean());
+        assert_eq!(std::fs::metadata(dir.join("0.data")).unwrap().len(), offset);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap(), None);
+        cask.put("a", b"3").unwrap();
+        drop(cask);
+
+        repair(&dir, &verify(&dir).unwrap(), Repair::Rewrite).unwrap();
+        let report = verify(&dir).unwrap();
+        assert!(report.is_clean());
+        // The merged data file and the new active one
+        assert_eq!(report.segments.len(), 2);
+        assert_eq!(report.segments[0].records, 1);
+        assert_eq!(report.segments[0].orphaned_records, 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_rewrite_corrupt() {
+        let (dir, _) = two_entries("verify-rewrite");
+        let mut data = std::fs::read(dir.join("0.data")).unwrap();
+        *data.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(dir.join("0.data"), &data).unwrap();
+
+        repair(&dir, &verify(&dir).unwrap(), Repair::Rewrite).unwrap();
+        let report = verify(&dir).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.segments.len(), 2);
+        assert_eq!(report.segments[0].records, 1);
+
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cask.get("b").unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_orphaned_across_segments() {
+        let (dir, _) = two_entries("verify-orphaned");
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        cask.merge().unwrap();
+        // 1.data holds `a` and `b`, 2.data overwrites `a` then deletes `b`
+        cask.put("a", b"2").unwrap();
+        cask.delete("b").unwrap();
+        drop(cask);
+
+        let report = verify(&dir).unwrap();
+        let orphaned: Vec<_> = report
+            .segments
+            .iter()
+            .map(|segment| (segment.file_id, segment.orphaned_records))
+            .collect();
+        assert_eq!(orphaned, vec![(1, 2), (2, 0)]);
+        assert_eq!(
+            report.segments[0].orphaned_bytes,
+            2 * Format::Checksummed.entry_size(1, 1)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_data_file() {
+        let dir = temp_data_dir("verify-legacy");
+        std::fs::create_dir(&dir).unwrap();
+        let mut entry = 0i64.to_be_bytes().to_vec();
+        entry.extend_from_slice(&1u32.to_be_bytes());
+        entry.extend_from_slice(&1u32.to_be_bytes());
+        entry.extend_from_slice(b"a1");
+        std::fs::write(dir.join("0.data"), &entry).unwrap();
+
+        let report = verify(&dir).unwrap();
+        assert!(report.is_clean());
+        assert!(!report.segments[0].checksums);
+        assert_eq!(report.segments[0].records, 1);
+
+        // The legacy file is read but new entries go to a data file with checksums
+        let mut cask = BitCask::open(dir.clone()).unwrap();
+        assert_eq!(cask.get("a").unwrap().as_deref(), Some("1"));
+        cask.put("b", b"2").unwrap();
+        cask.merge().unwrap();
+        drop(cask);
+
+        let report = verify(&dir).unwrap();
+        assert_eq!(report.segments.len(), 2);
+        assert!(report.segments[0].checksums);
+        assert_eq!(report.segments[0].records, 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,13 +1,26 @@
-#[derive(Copy, Clone, Debug, PartialEq)]
+use std::borrow::Cow;
+
+/// A parsed command. Arguments borrow from the input unless they had to be unescaped
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command<'a> {
+    Acl(AclCommand<'a>),
+    /// `AUTH [username] password`
+    Auth(Option<Cow<'a, str>>, Cow<'a, str>),
+    Client(ClientCommand<'a>),
     DbSize,
     Command(SubCommand),
-    Echo(&'a str),
-    Get(&'a str),
-    Keys(Option<&'a str>),
-    Set(&'a str, &'a [u8]),
+    Config(ConfigCommand<'a>),
+    Del(Vec<Cow<'a, str>>),
+    Echo(Cow<'a, str>),
+    FlushAll,
+    Get(Cow<'a, str>),
+    Hello(Hello<'a>),
+    Info(Option<Cow<'a, str>>),
+    Keys(Option<Cow<'a, str>>),
+    Set(Cow<'a, str>, Cow<'a, [u8]>),
     Ping,
     Quit,
+    Shutdown(Option<ShutdownMode>),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -15,20 +28,633 @@ pub enum SubCommand {
     Docs,
 }
 
-impl Command<'_> {
-    pub fn all_commands() -> &'static [(&'static str, &'static [&'static str])] {
-        &[
-            ("DBSIZE", &["Return the number of keys in the database."]),
-            (
-                "COMMAND DOCS",
-                &["Return documentary information about commands."],
+#[derive(Clone, Debug, PartialEq)]
+pub enum AclCommand<'a> {
+    List,
+    /// `ACL SETUSER username [rule ...]`
+    SetUser(Cow<'a, str>, Vec<Cow<'a, str>>),
+    WhoAmI,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientCommand<'a> {
+    Id,
+    Info,
+    List,
+    GetName,
+    /// `CLIENT SETNAME name`, an empty name clears it
+    SetName(Cow<'a, str>),
+    /// `CLIENT KILL addr`, the old form replying OK or an error
+    KillAddr(Cow<'a, str>),
+    /// `CLIENT KILL filter value [filter value ...]`, replying with the number of clients killed
+    Kill(KillFilter<'a>),
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hello<'a> {
+    pub version: Option<Cow<'a, str>>,
+    /// Username and password to authenticate as before switching protocol
+    pub auth: Option<(Cow<'a, str>, Cow<'a, str>)>,
+    pub name: Option<Cow<'a, str>>,
+}
+
+/// Clients CLIENT KILL closes, each filter that is set must match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KillFilter<'a> {
+    pub id: Option<u64>,
+    pub addr: Option<Cow<'a, str>>,
+    pub user: Option<Cow<'a, str>>,
+    /// Whether the calling client is spared, which it is unless `SKIPME no` is given
+    pub skipme: bool,
+}
+
+/// Parse the filters of CLIENT KILL, given as name and value pairs starting with `name` and `value`
+fn kill_filter<'a>(
+    mut name: Cow<'a, [u8]>,
+    mut value: Cow<'a, [u8]>,
+    mut args: Args<'a>,
+) -> Option<KillFilter<'a>> {
+    let mut filter = KillFilter {
+        skipme: true,
+        ..KillFilter::default()
+    };
+
+    loop {
+        let text = utf8(value)?;
+        if name.eq_ignore_ascii_case(b"id") {
+            filter.id = Some(text.parse().ok()?);
+        } else if name.eq_ignore_ascii_case(b"addr") {
+            filter.addr = Some(text);
+        } else if name.eq_ignore_ascii_case(b"user") {
+            filter.user = Some(text);
+        } else if name.eq_ignore_ascii_case(b"skipme") {
+            filter.skipme = match text.to_ascii_lowercase().as_str() {
+                "yes" => true,
+                "no" => false,
+                _ => return None,
+            };
+        } else {
+            return None;
+        }
+
+        match args.next() {
+            Some(next) => (name, value) = (next, args.next()?),
+            None => return Some(filter),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigCommand<'a> {
+    /// `CONFIG GET parameter [parameter ...]`, each a glob pattern
+    Get(Vec<Cow<'a, str>>),
+    /// `CONFIG SET parameter value [parameter value ...]`
+    Set(Vec<(Cow<'a, str>, Cow<'a, str>)>),
+    Rewrite,
+}
+
+/// Whether SHUTDOWN syncs the store to disk before exiting
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShutdownMode {
+    Save,
+    NoSave,
+}
+
+/// Why arguments do not form a valid command
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    /// No command has this name
+    Unknown(String),
+    /// The command exists but was given the wrong number of arguments
+    WrongArity(&'static str),
+    /// The arguments are not valid for the command
+    Syntax,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "unknown command '{}'", name),
+            CommandError::WrongArity(name) => write!(
+                f,
+                "wrong number of arguments for '{}'",
+                name.to_ascii_lowercase()
             ),
-            ("ECHO", &["Returns message."]),
-            ("GET", &["Get the value of key."]),
-            ("KEYS", &["Get all keys matching a regex pattern."]),
-            ("SET", &["Set the value of key."]),
-            ("PING", &["Pong."]),
-            ("QUIT", &["Ask the server to close the connection."]),
-        ]
+            CommandError::Syntax => write!(f, "syntax error"),
+        }
+    }
+}
+
+/// Arguments of a command, without its name
+pub type Args<'a> = std::vec::IntoIter<Cow<'a, [u8]>>;
+
+/// Entry of the command table
+pub struct CommandSpec {
+    /// Name of the command, matched case-insensitively
+    pub name: &'static str,
+    /// Number of arguments including the name, negative when it is a minimum, like Redis
+    pub arity: i8,
+    /// ACL categories, without the leading `@`
+    pub categories: &'static [&'static str],
+    /// Subcommands users may be allowed or denied one by one, empty when the command is one unit
+    pub subcommands: &'static [SubcommandSpec],
+    pub docs: &'static [&'static str],
+    parse: for<'a> fn(Args<'a>) -> Option<Command<'a>>,
+}
+
+/// Subcommand of the command table, such as `CLIENT ID`
+pub struct SubcommandSpec {
+    /// Name of the subcommand, matched case-insensitively
+    pub name: &'static str,
+    /// ACL categories, without the leading `@`, used instead of those of the command
+    pub categories: &'static [&'static str],
+}
+
+impl CommandSpec {
+    /// Whether `len` arguments, including the name, satisfy the arity
+    pub fn accepts(&self, len: usize) -> bool {
+        match self.arity {
+            arity if arity < 0 => len >= arity.unsigned_abs() as usize,
+            arity => len == arity as usize,
+        }
+    }
+}
+
+fn utf8(arg: Cow<'_, [u8]>) -> Option<Cow<'_, str>> {
+    match arg {
+        Cow::Borrowed(arg) => std::str::from_utf8(arg).ok().map(Cow::Borrowed),
+        Cow::Owned(arg) => String::from_utf8(arg).ok().map(Cow::Owned),
+    }
+}
+
+/// Parse an optional last argument
+fn optional(mut args: Args<'_>) -> Option<Option<Cow<'_, str>>> {
+    let arg = args.next().map(utf8);
+    match args.next() {
+        Some(_) => None,
+        None => arg.map_or(Some(None), |arg| arg.map(Some)),
+    }
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "ACL",
+        arity: -2,
+        categories: &["admin", "slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Manage users, only LIST, SETUSER and WHOAMI are supported."],
+        parse: |mut args| {
+            let sub = args.next()?;
+            if sub.eq_ignore_ascii_case(b"list") {
+                args.next()
+                    .is_none()
+                    .then_some(Command::Acl(AclCommand::List))
+            } else if sub.eq_ignore_ascii_case(b"whoami") {
+                args.next()
+                    .is_none()
+                    .then_some(Command::Acl(AclCommand::WhoAmI))
+            } else if sub.eq_ignore_ascii_case(b"setuser") {
+                let name = utf8(args.next()?)?;
+                let rules = args.map(utf8).collect::<Option<_>>()?;
+                Some(Command::Acl(AclCommand::SetUser(name, rules)))
+            } else {
+                None
+            }
+        },
+    },
+    CommandSpec {
+        name: "AUTH",
+        arity: -2,
+        categories: &["fast", "connection"],
+        subcommands: &[],
+        docs: &[
+            "Authenticate the connection as a user, the default user when no username is given.",
+        ],
+        parse: |mut args| {
+            let first = utf8(args.next()?)?;
+            match args.next() {
+                None => Some(Command::Auth(None, first)),
+                Some(password) => args
+                    .next()
+                    .is_none()
+                    .then_some(Command::Auth(Some(first), utf8(password)?)),
+            }
+        },
+    },
+    CommandSpec {
+        name: "CLIENT",
+        arity: -2,
+        categories: &["slow", "connection"],
+        subcommands: &[
+            SubcommandSpec {
+                name: "ID",
+                categories: &["slow", "connection"],
+            },
+            SubcommandSpec {
+                name: "INFO",
+                categories: &["slow", "connection"],
+            },
+            SubcommandSpec {
+                name: "LIST",
+                categories: &["admin", "slow", "dangerous", "connection"],
+            },
+            SubcommandSpec {
+                name: "GETNAME",
+                categories: &["slow", "connection"],
+            },
+            SubcommandSpec {
+                name: "SETNAME",
+                categories: &["slow", "connection"],
+            },
+            SubcommandSpec {
+                name: "KILL",
+                categories: &["admin", "slow", "dangerous", "connection"],
+            },
+        ],
+        docs: &["Manage client connections, only ID, INFO, LIST, GETNAME, SETNAME and KILL are supported."],
+        parse: |mut args| {
+            let sub = args.next()?;
+            let command = if sub.eq_ignore_ascii_case(b"id") {
+                ClientCommand::Id
+            } else if sub.eq_ignore_ascii_case(b"info") {
+                ClientCommand::Info
+            } else if sub.eq_ignore_ascii_case(b"list") {
+                ClientCommand::List
+            } else if sub.eq_ignore_ascii_case(b"getname") {
+                ClientCommand::GetName
+            } else if sub.eq_ignore_ascii_case(b"setname") {
+                ClientCommand::SetName(utf8(args.next()?)?)
+            } else if sub.eq_ignore_ascii_case(b"kill") {
+                let first = args.next()?;
+                match args.next() {
+                    None => ClientCommand::KillAddr(utf8(first)?),
+                    Some(value) => {
+                        let filter = kill_filter(first, value, args)?;
+                        return Some(Command::Client(ClientCommand::Kill(filter)));
+                    }
+                }
+            } else {
+                return None;
+            };
+            args.next().is_none().then_some(Command::Client(command))
+        },
+    },
+    CommandSpec {
+        name: "DBSIZE",
+        arity: 1,
+        categories: &["keyspace", "read", "fast"],
+        subcommands: &[],
+        docs: &["Return the number of keys in the database."],
+        parse: |_| Some(Command::DbSize),
+    },
+    CommandSpec {
+        name: "COMMAND",
+        arity: 2,
+        categories: &["slow", "connection"],
+        subcommands: &[],
+        docs: &["Return documentary information about commands, only DOCS is supported."],
+        parse: |mut args| {
+            let sub = args.next()?;
+            sub.eq_ignore_ascii_case(b"docs")
+                .then_some(Command::Command(SubCommand::Docs))
+        },
+    },
+    CommandSpec {
+        name: "CONFIG",
+        arity: -2,
+        categories: &["admin", "slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Get or set configuration at runtime, or write it to the configuration file."],
+        parse: |mut args| {
+            let sub = args.next()?;
+            if sub.eq_ignore_ascii_case(b"get") {
+                let patterns: Vec<_> = args.map(utf8).collect::<Option<_>>()?;
+                (!patterns.is_empty()).then_some(Command::Config(ConfigCommand::Get(patterns)))
+            } else if sub.eq_ignore_ascii_case(b"set") {
+                let mut pairs = Vec::new();
+                while let Some(key) = args.next() {
+                    pairs.push((utf8(key)?, utf8(args.next()?)?));
+                }
+                (!pairs.is_empty()).then_some(Command::Config(ConfigCommand::Set(pairs)))
+            } else if sub.eq_ignore_ascii_case(b"rewrite") {
+                args.next()
+                    .is_none()
+                    .then_some(Command::Config(ConfigCommand::Rewrite))
+            } else {
+                None
+            }
+        },
+    },
+    CommandSpec {
+        name: "DEL",
+        arity: -2,
+        categories: &["keyspace", "write", "slow"],
+        subcommands: &[],
+        docs: &["Delete keys, replying with the number of keys that existed."],
+        parse: |args| Some(Command::Del(args.map(utf8).collect::<Option<_>>()?)),
+    },
+    CommandSpec {
+        name: "ECHO",
+        arity: 2,
+        categories: &["fast", "connection"],
+        subcommands: &[],
+        docs: &["Returns message."],
+        parse: |mut args| Some(Command::Echo(utf8(args.next()?)?)),
+    },
+    CommandSpec {
+        name: "FLUSHALL",
+        arity: -1,
+        categories: &["keyspace", "write", "slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Delete every key, ASYNC and SYNC are accepted and behave the same."],
+        parse: |mut args| {
+            match args.next() {
+                None => (),
+                Some(mode)
+                    if mode.eq_ignore_ascii_case(b"async") || mode.eq_ignore_ascii_case(b"sync") => {}
+                Some(_) => return None,
+            }
+            args.next().is_none().then_some(Command::FlushAll)
+        },
+    },
+    CommandSpec {
+        name: "GET",
+        arity: 2,
+        categories: &["read", "string", "fast"],
+        subcommands: &[],
+        docs: &["Get the value of key."],
+        parse: |mut args| Some(Command::Get(utf8(args.next()?)?)),
+    },
+    CommandSpec {
+        name: "HELLO",
+        arity: -1,
+        categories: &["fast", "connection"],
+        subcommands: &[],
+        docs: &["Handshake with the server, optionally switching protocol version, authenticating and naming the connection."],
+        parse: |mut args| {
+            let mut hello = Hello::default();
+            if let Some(version) = args.next() {
+                hello.version = Some(utf8(version)?);
+            }
+            while let Some(clause) = args.next() {
+                if clause.eq_ignore_ascii_case(b"auth") {
+                    hello.auth = Some((utf8(args.next()?)?, utf8(args.next()?)?));
+                } else if clause.eq_ignore_ascii_case(b"setname") {
+                    hello.name = Some(utf8(args.next()?)?);
+                } else {
+                    return None;
+                }
+            }
+            Some(Command::Hello(hello))
+        },
+    },
+    CommandSpec {
+        name: "INFO",
+        arity: -1,
+        categories: &["slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Return information and statistics about the server."],
+        parse: |args| Some(Command::Info(optional(args)?)),
+    },
+    CommandSpec {
+        name: "KEYS",
+        arity: -1,
+        categories: &["keyspace", "read", "slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Get all keys matching a regex pattern."],
+        parse: |args| Some(Command::Keys(optional(args)?)),
+    },
+    CommandSpec {
+        name: "SET",
+        arity: 3,
+        categories: &["write", "string", "slow"],
+        subcommands: &[],
+        docs: &["Set the value of key."],
+        parse: |mut args| Some(Command::Set(utf8(args.next()?)?, args.next()?)),
+    },
+    CommandSpec {
+        name: "PING",
+        arity: 1,
+        categories: &["fast", "connection"],
+        subcommands: &[],
+        docs: &["Pong."],
+        parse: |_| Some(Command::Ping),
+    },
+    CommandSpec {
+        name: "QUIT",
+        arity: 1,
+        categories: &["fast", "connection"],
+        subcommands: &[],
+        docs: &["Ask the server to close the connection."],
+        parse: |_| Some(Command::Quit),
+    },
+    CommandSpec {
+        name: "SHUTDOWN",
+        arity: -1,
+        categories: &["admin", "slow", "dangerous"],
+        subcommands: &[],
+        docs: &["Stop the server, syncing the store to disk unless NOSAVE is given."],
+        parse: |mut args| {
+            let mode = match args.next() {
+                None => None,
+                Some(mode) if mode.eq_ignore_ascii_case(b"save") => Some(ShutdownMode::Save),
+                Some(mode) if mode.eq_ignore_ascii_case(b"nosave") => Some(ShutdownMode::NoSave),
+                Some(_) => return None,
+            };
+            args.next().is_none().then_some(Command::Shutdown(mode))
+        },
+    },
+];
+
+impl<'a> Command<'a> {
+    /// The command table shared by every protocol
+    pub fn all_commands() -> &'static [CommandSpec] {
+        COMMANDS
+    }
+
+    /// Look up a command by name, ignoring case
+    pub fn spec(name: &[u8]) -> Option<&'static CommandSpec> {
+        COMMANDS
+            .iter()
+            .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+    }
+
+    /// Name of the command in the command table
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Acl(_) => "ACL",
+            Command::Auth(..) => "AUTH",
+            Command::Client(_) => "CLIENT",
+            Command::DbSize => "DBSIZE",
+            Command::Command(_) => "COMMAND",
+            Command::Config(_) => "CONFIG",
+            Command::Del(_) => "DEL",
+            Command::Echo(_) => "ECHO",
+            Command::FlushAll => "FLUSHALL",
+            Command::Get(_) => "GET",
+            Command::Hello(_) => "HELLO",
+            Command::Info(_) => "INFO",
+            Command::Keys(_) => "KEYS",
+            Command::Set(..) => "SET",
+            Command::Ping => "PING",
+            Command::Quit => "QUIT",
+            Command::Shutdown(_) => "SHUTDOWN",
+        }
+    }
+
+    /// Name of the subcommand in the command table, for commands with subcommands
+    pub fn subcommand(&self) -> Option<&'static str> {
+        match self {
+            Command::Client(command) => Some(match command {
+                ClientCommand::Id => "ID",
+                ClientCommand::Info => "INFO",
+                ClientCommand::List => "LIST",
+                ClientCommand::GetName => "GETNAME",
+                ClientCommand::SetName(_) => "SETNAME",
+                ClientCommand::KillAddr(_) | ClientCommand::Kill(_) => "KILL",
+            }),
+            _ => None,
+        }
+    }
+
+    /// Keys the command reads or writes
+    pub fn keys(&self) -> &[Cow<'a, str>] {
+        match self {
+            Command::Get(key) | Command::Set(key, _) => std::slice::from_ref(key),
+            Command::Del(keys) => keys,
+            _ => &[],
+        }
+    }
+
+    /// Build a command from its arguments, the first being the command name
+    pub fn from_args(args: Vec<Cow<'a, [u8]>>) -> Result<Command<'a>, CommandError> {
+        let name = args.first().map(|name| name.as_ref()).unwrap_or_default();
+        let spec = Self::spec(name)
+            .ok_or_else(|| CommandError::Unknown(String::from_utf8_lossy(name).into_owned()))?;
+        if !spec.accepts(args.len()) {
+            return Err(CommandError::WrongArity(spec.name));
+        }
+
+        let mut args = args.into_iter();
+        args.next();
+        (spec.parse)(args).ok_or(CommandError::Syntax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args<'a>(args: &[&'a str]) -> Vec<Cow<'a, [u8]>> {
+        args.iter()
+            .map(|arg| Cow::Borrowed(arg.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(
+            Command::from_args(args(&["get", "key"])),
+            Ok(Command::Get("key".into()))
+        );
+        assert_eq!(
+            Command::from_args(args(&["Command", "docs"])),
+            Ok(Command::Command(SubCommand::Docs))
+        );
+        assert_eq!(
+            Command::from_args(args(&["HELLO", "3"])),
+            Ok(Command::Hello(Hello {
+                version: Some("3".into()),
+                ..Hello::default()
+            }))
+        );
+        assert_eq!(
+            Command::from_args(args(&["hello", "2", "setname", "worker", "AUTH", "u", "p"])),
+            Ok(Command::Hello(Hello {
+                version: Some("2".into()),
+                auth: Some(("u".into(), "p".into())),
+                name: Some("worker".into()),
+            }))
+        );
+        assert!(Command::from_args(args(&["HELLO", "3", "AUTH", "u"])).is_err());
+        assert!(Command::from_args(args(&["HELLO", "3", "FOO"])).is_err());
+        assert_eq!(
+            Command::from_args(args(&["del", "a", "b"])),
+            Ok(Command::Del(vec!["a".into(), "b".into()]))
+        );
+        assert_eq!(
+            Command::from_args(args(&["FLUSHALL", "async"])),
+            Ok(Command::FlushAll)
+        );
+        assert_eq!(
+            Command::from_args(args(&["AUTH", "alice", "secret"])),
+            Ok(Command::Auth(Some("alice".into()), "secret".into()))
+        );
+        assert_eq!(
+            Command::from_args(args(&["acl", "setuser", "alice", "on", "~*"])),
+            Ok(Command::Acl(AclCommand::SetUser(
+                "alice".into(),
+                vec!["on".into(), "~*".into()]
+            )))
+        );
+        assert_eq!(
+            Command::from_args(args(&["client", "kill", "user", "alice", "skipme", "no"])),
+            Ok(Command::Client(ClientCommand::Kill(KillFilter {
+                user: Some("alice".into()),
+                ..KillFilter::default()
+            })))
+        );
+        assert_eq!(
+            Command::from_args(args(&["CLIENT", "KILL", "ID", "x"])),
+            Err(CommandError::Syntax)
+        );
+        assert_eq!(
+            Command::from_args(args(&["config", "set", "appendfsync", "always"])),
+            Ok(Command::Config(ConfigCommand::Set(vec![(
+                "appendfsync".into(),
+                "always".into()
+            )])))
+        );
+        assert_eq!(
+            Command::from_args(args(&["CONFIG", "SET", "appendfsync"])),
+            Err(CommandError::Syntax)
+        );
+        assert_eq!(
+            Command::from_args(args(&["shutdown", "NoSave"])),
+            Ok(Command::Shutdown(Some(ShutdownMode::NoSave)))
+        );
+        assert_eq!(
+            Command::from_args(args(&["GET"])),
+            Err(CommandError::WrongArity("GET"))
+        );
+        assert_eq!(
+            Command::from_args(args(&["KEYS", "a", "b"])),
+            Err(CommandError::Syntax)
+        );
+        assert_eq!(
+            Command::from_args(args(&["FLUSHDB"])),
+            Err(CommandError::Unknown("FLUSHDB".into()))
+        );
+    }
+
+    #[test]
+    fn test_subcommand() {
+        for (line, subcommand) in [
+            (&["client", "id"][..], "ID"),
+            (&["client", "setname", "worker"], "SETNAME"),
+            (&["client", "kill", "127.0.0.1:6379"], "KILL"),
+            (&["client", "kill", "id", "1"], "KILL"),
+        ] {
+            let command = Command::from_args(args(line)).unwrap();
+            assert_eq!(command.subcommand(), Some(subcommand));
+            // Every subcommand is in the command table
+            assert!(Command::spec(command.name().as_bytes())
+                .unwrap()
+                .subcommands
+                .iter()
+                .any(|spec| spec.name == subcommand));
+        }
+        assert_eq!(Command::Ping.subcommand(), None);
     }
 }
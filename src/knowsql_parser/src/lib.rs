@@ -1,19 +1,126 @@
-use nom::{branch::alt, IResult};
+use command::{Command, CommandError};
+use nom::error::ErrorKind;
+use protocol::Limits;
 
 pub mod command;
+pub mod memcache;
 pub mod resp2;
 pub mod simple;
 
 pub mod protocol;
 
-/// Parse a command from a string. Attempt to parse as RESP2 first, then fallback to simple.
-pub fn parse_command(input: &[u8]) -> IResult<&[u8], command::Command> {
-    alt((simple::parse_command, resp2::parse_command))(input)
+/// Why [`parse_command()`] did not return a command
+#[derive(Debug, PartialEq)]
+pub enum ParseError<'a> {
+    /// The input ends before the frame does, more input is needed
+    Incomplete,
+    /// The frame is well formed but not a valid command, parsing can continue from `remaining`
+    Command {
+        remaining: &'a [u8],
+        error: CommandError,
+    },
+    /// The input does not follow the protocol, there is no way to find the next frame
+    Protocol(&'static str),
 }
 
-pub fn try_parse_command(input: &[u8]) -> Option<command::Command> {
+/// Parse a command from a string. Like Redis, input starting with `*` is a RESP2 array and anything
+/// else is an inline command. Empty frames are skipped.
+pub fn parse_command(input: &[u8]) -> Result<(&[u8], Command<'_>), ParseError<'_>> {
+    parse_command_with(input, &Limits::default())
+}
+
+/// Parse a command, see [`parse_command()`], rejecting frames above the given limits
+pub fn parse_command_with<'a>(
+    mut input: &'a [u8],
+    limits: &Limits,
+) -> Result<(&'a [u8], Command<'a>), ParseError<'a>> {
+    loop {
+        let (args, error) = match input.first() {
+            Some(b'*') => (
+                resp2::parse_args(input, limits),
+                "invalid multibulk request",
+            ),
+            _ => (
+                simple::parse_args(input, limits),
+                "unbalanced quotes in request",
+            ),
+        };
+
+        let (remaining, args) = match args {
+            Ok((remaining, args)) => (remaining, args),
+            Err(nom::Err::Incomplete(_)) => return Err(ParseError::Incomplete),
+            Err(nom::Err::Error(err) | nom::Err::Failure(err))
+                if err.code == ErrorKind::TooLarge =>
+            {
+                return Err(ParseError::Protocol("request exceeds protocol limits"))
+            }
+            Err(_) => return Err(ParseError::Protocol(error)),
+        };
+        if args.is_empty() {
+            input = remaining;
+            continue;
+        }
+
+        return match Command::from_args(args) {
+            Ok(command) => Ok((remaining, command)),
+            Err(error) => Err(ParseError::Command { remaining, error }),
+        };
+    }
+}
+
+pub fn try_parse_command(input: &[u8]) -> Option<Command<'_>> {
     match parse_command(input) {
         Ok((_, command)) => Some(command),
         Err(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_command(b"*2\r\n$3\r\nGET\r\n$3\r\nke"),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            parse_command(b"*0\r\n*1\r\n$3\r\nFOO\r\nPING\r\n"),
+            Err(ParseError::Command {
+                remaining: b"PING\r\n",
+                error: CommandError::Unknown("FOO".into())
+            })
+        );
+        assert_eq!(
+            parse_command(b"get\r\n"),
+            Err(ParseError::Command {
+                remaining: b"",
+                error: CommandError::WrongArity("GET")
+            })
+        );
+        assert!(matches!(
+            parse_command(b"*1\r\n:1\r\n"),
+            Err(ParseError::Protocol(_))
+        ));
+        assert!(matches!(
+            parse_command(b"ECHO \"hi\r\n"),
+            Err(ParseError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_large_value() {
+        let value = vec![b'x'; 2 * 1024 * 1024];
+        let mut request =
+            format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n", value.len()).into_bytes();
+        request.extend_from_slice(&value);
+
+        assert_eq!(parse_command(&request), Err(ParseError::Incomplete));
+        request.extend_from_slice(b"\r\n");
+        assert_eq!(
+            parse_command(&request),
+            Ok((&b""[..], Command::Set("k".into(), value.into())))
+        );
+    }
+}
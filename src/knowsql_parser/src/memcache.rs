@@ -0,0 +1,298 @@
+//! Memcached text protocol requests
+//! https://github.com/memcached/memcached/blob/master/doc/protocol.txt
+use crate::protocol::Limits;
+
+use nom::bytes::streaming::{tag, take, take_until};
+
+/// Longest key memcached accepts
+pub const MAX_KEY_LEN: usize = 250;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StoreMode {
+    /// Store unconditionally
+    Set,
+    /// Store only if the key does not exist
+    Add,
+    /// Store only if the key exists
+    Replace,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request<'a> {
+    /// `get`, or `gets` when `cas` is set
+    Get {
+        keys: Vec<&'a str>,
+        cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: &'a str,
+        flags: u32,
+        exptime: i64,
+        value: &'a [u8],
+        noreply: bool,
+    },
+    Delete {
+        key: &'a str,
+        noreply: bool,
+    },
+    /// `incr`, or `decr` when `delta` is negative
+    Incr {
+        key: &'a str,
+        delta: i128,
+        noreply: bool,
+    },
+    Touch {
+        key: &'a str,
+        exptime: i64,
+        noreply: bool,
+    },
+    FlushAll {
+        noreply: bool,
+    },
+    Version,
+    Stats,
+    Quit,
+}
+
+/// Why [`parse_request()`] did not return a request
+#[derive(Debug, PartialEq)]
+pub enum RequestError<'a> {
+    /// The input ends before the request does, more input is needed
+    Incomplete,
+    /// No command has this name, answered with `ERROR`. Parsing can continue from `remaining`
+    Unknown { remaining: &'a [u8] },
+    /// The request is malformed, answered with `CLIENT_ERROR`. Parsing can continue from `remaining`
+    Client {
+        remaining: &'a [u8],
+        message: &'static str,
+    },
+    /// The request exceeds the protocol limits, there is no way to find the next request
+    TooLarge,
+}
+
+fn key(key: &[u8]) -> Result<&str, &'static str> {
+    if key.len() > MAX_KEY_LEN {
+        return Err("key too long");
+    }
+    std::str::from_utf8(key).map_err(|_| "key is not valid utf8")
+}
+
+fn number<T: std::str::FromStr>(arg: &[u8]) -> Result<T, &'static str> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or("bad command line format")
+}
+
+/// Whether the last argument is `noreply`, removing it if so
+fn noreply(args: &mut Vec<&[u8]>) -> bool {
+    let noreply = args.last() == Some(&&b"noreply"[..]);
+    if noreply {
+        args.pop();
+    }
+    noreply
+}
+
+/// Parse one request, including the data block of storage commands
+pub fn parse_request<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+) -> Result<(&'a [u8], Request<'a>), RequestError<'a>> {
+    let (remaining, line) = match take_until::<_, _, nom::error::Error<_>>("\n")(input) {
+        Ok((remaining, line)) if line.len() <= limits.max_inline_len => (remaining, line),
+        Err(nom::Err::Incomplete(_)) if input.len() <= limits.max_inline_len => {
+            return Err(RequestError::Incomplete)
+        }
+        _ => return Err(RequestError::TooLarge),
+    };
+    let remaining = &remaining[1..];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    let mut args: Vec<&[u8]> = line
+        .split(|c| *c == b' ')
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    if args.is_empty() {
+        return Err(RequestError::Unknown { remaining });
+    }
+    let name = args.remove(0);
+
+    let client_error = |message| RequestError::Client { remaining, message };
+    let request = match (name, args.len()) {
+        (b"get" | b"gets", 1..) => Request::Get {
+            keys: args
+                .into_iter()
+                .map(key)
+                .collect::<Result<_, _>>()
+                .map_err(client_error)?,
+            cas: name == b"gets",
+        },
+        (b"set" | b"add" | b"replace", 4..=5) => {
+            let noreply = noreply(&mut args);
+            let [k, flags, exptime, bytes] = args[..] else {
+                return Err(client_error("bad command line format"));
+            };
+            let bytes: usize = number(bytes).map_err(client_error)?;
+            if bytes > limits.max_bulk_len {
+                return Err(RequestError::TooLarge);
+            }
+
+            // The data block follows the command line
+            let (after, value) = match take::<_, _, nom::error::Error<_>>(bytes)(remaining) {
+                Ok(parsed) => parsed,
+                Err(_) => return Err(RequestError::Incomplete),
+            };
+            let after = match tag::<_, _, nom::error::Error<_>>("\r\n")(after) {
+                Ok((after, _)) => after,
+                Err(nom::Err::Incomplete(_)) => return Err(RequestError::Incomplete),
+                Err(_) => {
+                    return Err(RequestError::Client {
+                        remaining: after,
+                        message: "bad data chunk",
+                    })
+                }
+            };
+
+            // Skip the data block along with the request when the command line is invalid
+            let client_error = |message| RequestError::Client {
+                remaining: after,
+                message,
+            };
+            let request = Request::Store {
+                mode: match name {
+                    b"set" => StoreMode::Set,
+                    b"add" => StoreMode::Add,
+                    _ => StoreMode::Replace,
+                },
+                key: key(k).map_err(client_error)?,
+                flags: number(flags).map_err(client_error)?,
+                exptime: number(exptime).map_err(client_error)?,
+                value,
+                noreply,
+            };
+            return Ok((after, request));
+        }
+        (b"delete", 1..=2) => {
+            let noreply = noreply(&mut args);
+            let [k] = args[..] else {
+                return Err(client_error("bad command line format"));
+            };
+            Request::Delete {
+                key: key(k).map_err(client_error)?,
+                noreply,
+            }
+        }
+        (b"incr" | b"decr", 2..=3) => {
+            let noreply = noreply(&mut args);
+            let [k, delta] = args[..] else {
+                return Err(client_error("bad command line format"));
+            };
+            let delta: u64 =
+                number(delta).map_err(|_| client_error("invalid numeric delta argument"))?;
+            Request::Incr {
+                key: key(k).map_err(client_error)?,
+                delta: match name {
+                    b"incr" => delta as i128,
+                    _ => -(delta as i128),
+                },
+                noreply,
+            }
+        }
+        (b"touch", 2..=3) => {
+            let noreply = noreply(&mut args);
+            let [k, exptime] = args[..] else {
+                return Err(client_error("bad command line format"));
+            };
+            Request::Touch {
+                key: key(k).map_err(client_error)?,
+                exptime: number(exptime).map_err(client_error)?,
+                noreply,
+            }
+        }
+        (b"flush_all", 0..=2) => {
+            let noreply = noreply(&mut args);
+            match args[..] {
+                [] => (),
+                // A delay is accepted but the flush is immediate
+                [delay] => {
+                    number::<u32>(delay).map_err(client_error)?;
+                }
+                _ => return Err(client_error("bad command line format")),
+            }
+            Request::FlushAll { noreply }
+        }
+        (b"version", 0) => Request::Version,
+        (b"stats", 0) => Request::Stats,
+        (b"quit", 0) => Request::Quit,
+        (
+            b"get" | b"gets" | b"set" | b"add" | b"replace" | b"delete" | b"incr" | b"decr"
+            | b"touch" | b"flush_all" | b"version" | b"stats" | b"quit",
+            _,
+        ) => return Err(client_error("bad command line format")),
+        _ => return Err(RequestError::Unknown { remaining }),
+    };
+
+    Ok((remaining, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let limits = Limits::default();
+
+        assert_eq!(
+            parse_request(b"gets a b\r\nversion\r\n", &limits),
+            Ok((
+                &b"version\r\n"[..],
+                Request::Get {
+                    keys: vec!["a", "b"],
+                    cas: true
+                }
+            ))
+        );
+        assert_eq!(
+            parse_request(b"add k 5 0 5 noreply\r\nhello\r\n", &limits),
+            Ok((
+                &b""[..],
+                Request::Store {
+                    mode: StoreMode::Add,
+                    key: "k",
+                    flags: 5,
+                    exptime: 0,
+                    value: b"hello",
+                    noreply: true
+                }
+            ))
+        );
+        assert_eq!(
+            parse_request(b"decr k 3\r\n", &limits),
+            Ok((
+                &b""[..],
+                Request::Incr {
+                    key: "k",
+                    delta: -3,
+                    noreply: false
+                }
+            ))
+        );
+        assert_eq!(
+            parse_request(b"set k 0 0 5\r\nhel", &limits),
+            Err(RequestError::Incomplete)
+        );
+        assert_eq!(
+            parse_request(b"set k 0 0 2\r\nhello\r\nstats\r\n", &limits),
+            Err(RequestError::Client {
+                remaining: b"llo\r\nstats\r\n",
+                message: "bad data chunk"
+            })
+        );
+        assert_eq!(
+            parse_request(b"cas k 0 0 1 1\r\n", &limits),
+            Err(RequestError::Unknown { remaining: b"" })
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Encoding of RESP values straight into a writer, without building intermediate strings
+
+use std::io::{Result, Write};
+
+/// Number of decimal digits needed to print `n`
+fn digits(mut n: u64) -> usize {
+    let mut digits = 1;
+    while n >= 10 {
+        n /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+/// Length of a `prefix` `n` `\r\n` line
+pub fn integer_len(n: i64) -> usize {
+    1 + (n < 0) as usize + digits(n.unsigned_abs()) + 2
+}
+
+/// Length of a `prefix` `data` `\r\n` line
+pub fn line_len(data: &[u8]) -> usize {
+    1 + data.len() + 2
+}
+
+/// Length of a bulk string holding `len` bytes
+pub fn bulk_len(len: usize) -> usize {
+    1 + digits(len as u64) + 2 + len + 2
+}
+
+/// Write a single line value, such as a simple string or error, after its type prefix
+pub fn write_line<W: Write + ?Sized>(writer: &mut W, prefix: u8, data: &[u8]) -> Result<()> {
+    writer.write_all(&[prefix])?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")
+}
+
+/// Write an integer, or any other length, after its type prefix
+pub fn write_integer<W: Write + ?Sized>(writer: &mut W, prefix: u8, n: i64) -> Result<()> {
+    write!(writer, "{}{}\r\n", prefix as char, n)
+}
+
+/// Write a length prefixed string, such as a bulk string, after its type prefix
+pub fn write_blob<W: Write + ?Sized>(writer: &mut W, prefix: u8, data: &[u8]) -> Result<()> {
+    write!(writer, "{}{}\r\n", prefix as char, data.len())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")
+}
+
+/// Write a bulk string
+pub fn write_bulk<W: Write + ?Sized>(writer: &mut W, data: &[u8]) -> Result<()> {
+    write_blob(writer, b'$', data)
+}
+
+/// Write the header of an array holding `len` items, the items are written by the caller
+pub fn write_array_header<W: Write + ?Sized>(writer: &mut W, len: usize) -> Result<()> {
+    write_integer(writer, b'*', len as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precomputed_lengths() {
+        for n in [0, 9, 10, -1, -10, i64::MAX, i64::MIN] {
+            let mut buf = Vec::new();
+            write_integer(&mut buf, b':', n).unwrap();
+            assert_eq!(buf.len(), integer_len(n));
+        }
+
+        let mut buf = Vec::new();
+        write_bulk(&mut buf, &[b'x'; 100]).unwrap();
+        assert_eq!(buf.len(), bulk_len(100));
+    }
+}
@@ -1 +1,43 @@
+pub mod encode;
 pub mod resp2;
+pub mod resp3;
+
+/// Protocol spoken on a connection, chosen by the client with HELLO
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// Bounds on the frames the parsers accept, so a client can not make the server panic or allocate
+/// unbounded memory. Exceeding a limit fails with [`nom::error::ErrorKind::TooLarge`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// Largest bulk string, in bytes
+    pub max_bulk_len: usize,
+    /// Most elements of an array, or pairs of a map
+    pub max_array_len: usize,
+    /// Deepest nesting of aggregates, a flat array has a depth of 1
+    pub max_depth: usize,
+    /// Longest inline command, in bytes
+    pub max_inline_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 32,
+            max_inline_len: 64 * 1024,
+        }
+    }
+}
+
+pub(crate) fn too_large(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Failure(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::TooLarge,
+    ))
+}
@@ -1,12 +1,16 @@
-///! Redis Serialization Protocol (RESP2) parser
-///! https://redis.io/docs/reference/protocol-spec/
+//! Redis Serialization Protocol (RESP2) parser
+//! https://redis.io/docs/reference/protocol-spec/
+use super::{encode, too_large, Limits};
 use nom::{
     branch::alt,
-    bytes::streaming::{tag_no_case, take},
-    character::complete::{digit1, line_ending, not_line_ending},
+    bytes::streaming::{tag, tag_no_case, take},
+    character::streaming::{digit1, line_ending, not_line_ending},
+    combinator::{map_res, opt, recognize},
     multi::count,
+    sequence::{pair, terminated},
     IResult,
 };
+use std::io::Write;
 
 #[derive(Debug, PartialEq)]
 pub enum Data<'a> {
@@ -15,120 +19,164 @@ pub enum Data<'a> {
     /// [Simple Error](https://redis.io/docs/reference/protocol-spec/#simple-errors)
     Error(&'a str),
     /// [Integer](https://redis.io/docs/reference/protocol-spec/#integers)
-    Integer(isize),
+    Integer(i64),
     /// [Bulk String](https://redis.io/docs/reference/protocol-spec/#bulk-strings)
     BulkString(&'a str),
+    /// [Null bulk string](https://redis.io/docs/reference/protocol-spec/#null-bulk-strings)
+    Null,
     /// [Array](https://redis.io/docs/reference/protocol-spec/#arrays)
     Array(Vec<Data<'a>>),
+    /// [Null array](https://redis.io/docs/reference/protocol-spec/#null-arrays)
+    NullArray,
 }
 
 impl Data<'_> {
-    /// Convert the data to a RESP2 string
-    pub fn as_str(&self) -> Option<String> {
+    /// Number of bytes [`Data::write_to()`] writes
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Data::String(data) | Data::Error(data) => encode::line_len(data.as_bytes()),
+            Data::Integer(data) => encode::integer_len(*data),
+            Data::BulkString(data) => encode::bulk_len(data.len()),
+            Data::Null | Data::NullArray => 5,
+            Data::Array(data) => {
+                encode::integer_len(data.len() as i64)
+                    + data.iter().map(Data::encoded_len).sum::<usize>()
+            }
+        }
+    }
+
+    /// Encode the data as RESP2 straight into a writer
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
         match self {
-            Data::String(data) => Some(format!("+{}\r\n", data)),
-            Data::Error(data) => Some(format!("-{}\r\n", data)),
-            Data::Integer(data) => Some(format!(":{}\r\n", data)),
-            Data::BulkString(data) => Some(format!("${}\r\n{}\r\n", data.len(), data)),
+            Data::String(data) => encode::write_line(writer, b'+', data.as_bytes()),
+            Data::Error(data) => encode::write_line(writer, b'-', data.as_bytes()),
+            Data::Integer(data) => encode::write_integer(writer, b':', *data),
+            Data::BulkString(data) => encode::write_bulk(writer, data.as_bytes()),
+            Data::Null => writer.write_all(b"$-1\r\n"),
+            Data::NullArray => writer.write_all(b"*-1\r\n"),
             Data::Array(data) => {
-                let mut result = String::from("*");
-                result.push_str(&data.len().to_string());
-                result.push_str("\r\n");
-                for item in data {
-                    if let Some(item_str) = item.as_str() {
-                        result.push_str(&item_str);
-                    } else {
-                        return None;
-                    }
-                }
-                Some(result)
+                encode::write_array_header(writer, data.len())?;
+                data.iter().try_for_each(|item| item.write_to(writer))
             }
         }
     }
+
+    /// Convert the data to a RESP2 string
+    pub fn as_str(&self) -> Option<String> {
+        let mut result = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut result).ok()?;
+        String::from_utf8(result).ok()
+    }
+}
+
+fn parse_line(input: &[u8]) -> IResult<&[u8], &str> {
+    map_res(
+        terminated(not_line_ending, line_ending),
+        std::str::from_utf8,
+    )(input)
 }
 
-fn parse_string(input: &[u8]) -> IResult<&[u8], Data> {
+fn parse_string(input: &[u8]) -> IResult<&[u8], Data<'_>> {
     let (input, _) = tag_no_case("+")(input)?;
-    let (input, data) = not_line_ending(input)?;
-    let (input, _) = line_ending(input)?;
-    Ok((
-        input,
-        Data::String(std::str::from_utf8(data).expect("data is valid utf8 string")),
-    ))
+    let (input, data) = parse_line(input)?;
+    Ok((input, Data::String(data)))
 }
 
-fn parse_error(input: &[u8]) -> IResult<&[u8], Data> {
+fn parse_error(input: &[u8]) -> IResult<&[u8], Data<'_>> {
     let (input, _) = tag_no_case("-")(input)?;
-    let (input, data) = not_line_ending(input)?;
-    let (input, _) = line_ending(input)?;
-    Ok((
-        input,
-        Data::Error(std::str::from_utf8(data).expect("data is valid utf8 string")),
-    ))
+    let (input, data) = parse_line(input)?;
+    Ok((input, Data::Error(data)))
 }
 
-fn parse_integer(input: &[u8]) -> IResult<&[u8], Data> {
+fn parse_integer(input: &[u8]) -> IResult<&[u8], Data<'_>> {
     let (input, _) = tag_no_case(":")(input)?;
-    let (input, data) = digit1(input)?;
-
-    // safety: digit1 ensures that the string is valid utf8
-    let data: isize = unsafe {
-        std::str::from_utf8_unchecked(data)
-            .parse()
-            .expect("string parsed with digit1 is a valid integer")
-    };
+    let (input, data) = map_res(
+        recognize(pair(opt(alt((tag("-"), tag("+")))), digit1)),
+        // safety: the sign and digit1 ensure that the string is valid utf8
+        |data| unsafe { std::str::from_utf8_unchecked(data) }.parse(),
+    )(input)?;
 
     let (input, _) = line_ending(input)?;
     Ok((input, Data::Integer(data)))
 }
 
-fn parse_bulk_string(input: &[u8]) -> IResult<&[u8], Data> {
-    let (input, _) = tag_no_case("$")(input)?;
-    let (input, length) = digit1(input)?;
-
-    // safety: digit1 ensures that the string is valid utf8
-    let length: usize = unsafe {
-        std::str::from_utf8_unchecked(length)
-            .parse()
-            .expect("string parsed with digit1 is a valid integer")
-    };
-
+fn parse_null(input: &[u8]) -> IResult<&[u8], Data<'_>> {
+    let (input, _) = tag_no_case("$-1")(input)?;
     let (input, _) = line_ending(input)?;
-    let (input, data) = take(length)(input)?;
+    Ok((input, Data::Null))
+}
+
+pub(crate) fn parse_null_array(input: &[u8]) -> IResult<&[u8], Data<'_>> {
+    let (input, _) = tag_no_case("*-1")(input)?;
     let (input, _) = line_ending(input)?;
-    Ok((
-        input,
-        Data::BulkString(std::str::from_utf8(data).expect("data is valid utf8 string")),
-    ))
+    Ok((input, Data::NullArray))
 }
 
-fn parse_array<'a>(input: &[u8]) -> IResult<&[u8], Data> {
-    let (input, _) = tag_no_case("*")(input)?;
-    let (input, length) = digit1(input)?;
+/// Parse the length of a bulk string or array, failing when it is above `max`
+pub(crate) fn parse_length(input: &[u8], max: usize) -> IResult<&[u8], usize> {
+    let (remaining, length) = digit1(input)?;
 
-    // safety: digit1 ensures that the string is valid utf8
-    let length = unsafe {
-        std::str::from_utf8_unchecked(length)
-            .parse()
-            .expect("string parsed with digit1 is a valid integer")
+    // safety: digit1 ensures that the string is valid utf8, so parsing only fails on overflow
+    let length = match unsafe { std::str::from_utf8_unchecked(length) }.parse() {
+        Ok(length) if length <= max => length,
+        _ => return Err(too_large(input)),
     };
 
+    let (remaining, _) = line_ending(remaining)?;
+    Ok((remaining, length))
+}
+
+/// Parse the raw bytes of a bulk string
+pub(crate) fn parse_bulk<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], &'a [u8]> {
+    let (input, _) = tag_no_case("$")(input)?;
+    let (input, length) = parse_length(input, limits.max_bulk_len)?;
+    let (input, data) = take(length)(input)?;
     let (input, _) = line_ending(input)?;
+    Ok((input, data))
+}
 
-    let (input, data) = count(parse_data, length)(input)?;
+fn parse_bulk_string<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], Data<'a>> {
+    let (input, data) = map_res(|input| parse_bulk(input, limits), std::str::from_utf8)(input)?;
+    Ok((input, Data::BulkString(data)))
+}
+
+fn parse_array<'a>(input: &'a [u8], limits: &Limits, depth: usize) -> IResult<&'a [u8], Data<'a>> {
+    let (input, _) = tag_no_case("*")(input)?;
+    if depth >= limits.max_depth {
+        return Err(too_large(input));
+    }
+    let (input, length) = parse_length(input, limits.max_array_len)?;
+
+    let (input, data) = count(|input| parse_data_at(input, limits, depth + 1), length)(input)?;
     Ok((input, Data::Array(data)))
 }
 
-pub fn parse_data(input: &[u8]) -> IResult<&[u8], Data> {
+fn parse_data_at<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    depth: usize,
+) -> IResult<&'a [u8], Data<'a>> {
     alt((
         parse_string,
         parse_error,
         parse_integer,
-        parse_bulk_string,
-        parse_array,
+        parse_null,
+        |input| parse_bulk_string(input, limits),
+        parse_null_array,
+        |input| parse_array(input, limits, depth),
     ))(input)
 }
 
+/// Parse data within the given limits
+pub fn parse_data_with<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], Data<'a>> {
+    parse_data_at(input, limits, 0)
+}
+
+/// Parse data within the [default limits](Limits::default())
+pub fn parse_data(input: &[u8]) -> IResult<&[u8], Data<'_>> {
+    parse_data_with(input, &Limits::default())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,6 +195,18 @@ mod tests {
             parse_data(":1000\r\n".as_bytes()),
             Ok(("".as_bytes(), Data::Integer(1000)))
         );
+        assert_eq!(
+            parse_data(":-5\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Integer(-5)))
+        );
+        assert_eq!(
+            parse_data("$-1\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Null))
+        );
+        assert_eq!(
+            parse_data("*-1\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::NullArray))
+        );
         assert_eq!(
             parse_data("$6\r\nfoobar\r\n".as_bytes()),
             Ok(("".as_bytes(), Data::BulkString("foobar")))
@@ -172,4 +232,38 @@ mod tests {
             ))
         )
     }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_bulk_len: 4,
+            max_array_len: 2,
+            max_depth: 2,
+            ..Limits::default()
+        };
+        let too_large = |result: IResult<&[u8], Data>| matches!(result, Err(nom::Err::Failure(err)) if err.code == nom::error::ErrorKind::TooLarge);
+
+        assert!(too_large(parse_data_with(b"$5\r\nhello\r\n", &limits)));
+        assert!(too_large(parse_data_with(b"*3\r\n", &limits)));
+        assert!(too_large(parse_data_with(b"*1\r\n*1\r\n*1\r\n", &limits)));
+        assert!(too_large(parse_data(b"$99999999999999999999999\r\n")));
+        assert!(parse_data_with(b"*1\r\n*1\r\n:1\r\n", &limits).is_ok());
+        assert!(matches!(
+            parse_data(b"$2\r\n\xff\xfe\r\n"),
+            Err(nom::Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let data = Data::Array(vec![
+            Data::Integer(-42),
+            Data::Null,
+            Data::NullArray,
+            Data::BulkString("foo"),
+        ]);
+        let encoded = data.as_str().unwrap();
+        assert_eq!(encoded, "*4\r\n:-42\r\n$-1\r\n*-1\r\n$3\r\nfoo\r\n");
+        assert_eq!(parse_data(encoded.as_bytes()), Ok(("".as_bytes(), data)));
+    }
 }
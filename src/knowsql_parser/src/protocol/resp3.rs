@@ -0,0 +1,405 @@
+//! Redis Serialization Protocol (RESP3) parser
+//! https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md
+use super::{encode, resp2, too_large, Limits};
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take},
+    character::streaming::{digit1, line_ending, not_line_ending},
+    combinator::{map, map_res, opt, recognize},
+    multi::count,
+    sequence::{pair, preceded, terminated},
+    IResult,
+};
+use std::io::{Error, ErrorKind, Write};
+
+#[derive(Debug, PartialEq)]
+pub enum Data<'a> {
+    /// [Simple string](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#simple-types)
+    String(&'a str),
+    /// [Simple error](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#simple-types)
+    Error(&'a str),
+    /// [Number](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#simple-types)
+    Integer(i64),
+    /// [Blob string](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#blob-types)
+    BulkString(&'a str),
+    /// [Array](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#aggregate-data-types)
+    Array(Vec<Data<'a>>),
+    /// [Null](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#null-reply)
+    Null,
+    /// [Boolean](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#boolean-reply)
+    Boolean(bool),
+    /// [Double](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#double-type)
+    Double(f64),
+    /// [Big number](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#big-number-type)
+    BigNumber(&'a str),
+    /// [Blob error](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#blob-types)
+    BulkError(&'a str),
+    /// [Verbatim string](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#verbatim-string-type)
+    /// of a three character format, such as `txt`, and its content
+    VerbatimString(&'a str, &'a str),
+    /// [Map](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#map-type)
+    Map(Vec<(Data<'a>, Data<'a>)>),
+    /// [Set](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#set-reply)
+    Set(Vec<Data<'a>>),
+    /// [Attribute](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#attribute-type),
+    /// auxiliary information sent ahead of a reply
+    Attribute(Vec<(Data<'a>, Data<'a>)>),
+    /// [Push](https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md#push-type)
+    Push(Vec<Data<'a>>),
+}
+
+fn encode_double(data: f64) -> String {
+    if data.is_nan() {
+        "nan".to_string()
+    } else if data.is_infinite() {
+        if data > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        data.to_string()
+    }
+}
+
+fn invalid_verbatim_format() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "verbatim string format must be three characters",
+    )
+}
+
+impl Data<'_> {
+    /// Number of bytes [`Data::write_to()`] writes
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Data::String(data) | Data::Error(data) | Data::BigNumber(data) => {
+                encode::line_len(data.as_bytes())
+            }
+            Data::Integer(data) => encode::integer_len(*data),
+            Data::BulkString(data) | Data::BulkError(data) => encode::bulk_len(data.len()),
+            Data::Null => 3,
+            Data::Boolean(_) => 4,
+            Data::Double(data) => encode::line_len(encode_double(*data).as_bytes()),
+            Data::VerbatimString(_, data) => encode::bulk_len(data.len() + 4),
+            Data::Array(data) | Data::Set(data) | Data::Push(data) => {
+                encode::integer_len(data.len() as i64)
+                    + data.iter().map(Data::encoded_len).sum::<usize>()
+            }
+            Data::Map(data) | Data::Attribute(data) => {
+                encode::integer_len(data.len() as i64)
+                    + data
+                        .iter()
+                        .map(|(key, value)| key.encoded_len() + value.encoded_len())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Encode the data as RESP3 straight into a writer
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            Data::String(data) => encode::write_line(writer, b'+', data.as_bytes()),
+            Data::Error(data) => encode::write_line(writer, b'-', data.as_bytes()),
+            Data::Integer(data) => encode::write_integer(writer, b':', *data),
+            Data::BulkString(data) => encode::write_bulk(writer, data.as_bytes()),
+            Data::Array(data) => {
+                write_aggregate(writer, b'*', data, |data, writer| data.write_to(writer))
+            }
+            Data::Null => writer.write_all(b"_\r\n"),
+            Data::Boolean(data) => writer.write_all(if *data { b"#t\r\n" } else { b"#f\r\n" }),
+            Data::Double(data) => encode::write_line(writer, b',', encode_double(*data).as_bytes()),
+            Data::BigNumber(data) => encode::write_line(writer, b'(', data.as_bytes()),
+            Data::BulkError(data) => encode::write_blob(writer, b'!', data.as_bytes()),
+            Data::VerbatimString(format, data) => {
+                if format.len() != 3 {
+                    return Err(invalid_verbatim_format());
+                }
+                write!(writer, "={}\r\n{}:", data.len() + 4, format)?;
+                writer.write_all(data.as_bytes())?;
+                writer.write_all(b"\r\n")
+            }
+            Data::Map(data) => {
+                write_pairs(writer, b'%', data, |data, writer| data.write_to(writer))
+            }
+            Data::Set(data) => {
+                write_aggregate(writer, b'~', data, |data, writer| data.write_to(writer))
+            }
+            Data::Attribute(data) => {
+                write_pairs(writer, b'|', data, |data, writer| data.write_to(writer))
+            }
+            Data::Push(data) => {
+                write_aggregate(writer, b'>', data, |data, writer| data.write_to(writer))
+            }
+        }
+    }
+
+    /// Encode the data as RESP2 straight into a writer, for clients that have not negotiated RESP3.
+    ///   types without a RESP2 equivalent are downgraded the same way Redis does
+    pub fn write_resp2_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            Data::Array(data) | Data::Set(data) | Data::Push(data) => {
+                write_aggregate(writer, b'*', data, |data, writer| {
+                    data.write_resp2_to(writer)
+                })
+            }
+            Data::Null => resp2::Data::Null.write_to(writer),
+            Data::Boolean(data) => resp2::Data::Integer(*data as i64).write_to(writer),
+            Data::Double(data) => encode::write_bulk(writer, encode_double(*data).as_bytes()),
+            Data::BigNumber(data) | Data::VerbatimString(_, data) => {
+                encode::write_bulk(writer, data.as_bytes())
+            }
+            Data::BulkError(data) => resp2::Data::Error(data).write_to(writer),
+            Data::Map(data) => {
+                encode::write_array_header(writer, data.len() * 2)?;
+                data.iter().try_for_each(|(key, value)| {
+                    key.write_resp2_to(writer)?;
+                    value.write_resp2_to(writer)
+                })
+            }
+            // Attributes are optional information, dropped rather than sent to RESP2 clients
+            Data::Attribute(_) => Ok(()),
+            data => data.write_to(writer),
+        }
+    }
+
+    /// Convert the data to a RESP3 string
+    pub fn as_str(&self) -> Option<String> {
+        let mut result = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut result).ok()?;
+        String::from_utf8(result).ok()
+    }
+
+    /// Convert the data to a RESP2 string, see [`Data::write_resp2_to()`]
+    pub fn as_resp2_str(&self) -> Option<String> {
+        let mut result = Vec::new();
+        self.write_resp2_to(&mut result).ok()?;
+        String::from_utf8(result).ok()
+    }
+}
+
+fn write_aggregate<W: Write + ?Sized>(
+    writer: &mut W,
+    prefix: u8,
+    data: &[Data],
+    write: impl Fn(&Data, &mut W) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    encode::write_integer(writer, prefix, data.len() as i64)?;
+    data.iter().try_for_each(|item| write(item, writer))
+}
+
+fn write_pairs<W: Write + ?Sized>(
+    writer: &mut W,
+    prefix: u8,
+    data: &[(Data, Data)],
+    write: impl Fn(&Data, &mut W) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    encode::write_integer(writer, prefix, data.len() as i64)?;
+    data.iter().try_for_each(|(key, value)| {
+        write(key, writer)?;
+        write(value, writer)
+    })
+}
+
+fn line(input: &[u8]) -> IResult<&[u8], &str> {
+    map_res(
+        terminated(not_line_ending, line_ending),
+        std::str::from_utf8,
+    )(input)
+}
+
+fn length(input: &[u8], max: usize) -> IResult<&[u8], usize> {
+    let (remaining, length) = line(input)?;
+    match length.parse() {
+        Ok(length) if length <= max => Ok((remaining, length)),
+        Ok(_) => Err(too_large(input)),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn blob<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], &'a str> {
+    let (input, length) = length(input, limits.max_bulk_len)?;
+    map_res(terminated(take(length), line_ending), std::str::from_utf8)(input)
+}
+
+fn nested<'a>(input: &'a [u8], limits: &Limits, depth: usize) -> IResult<&'a [u8], usize> {
+    if depth >= limits.max_depth {
+        return Err(too_large(input));
+    }
+    length(input, limits.max_array_len)
+}
+
+fn pairs<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    depth: usize,
+) -> IResult<&'a [u8], Vec<(Data<'a>, Data<'a>)>> {
+    let (input, length) = nested(input, limits, depth)?;
+    let data = |input| parse_data_at(input, limits, depth + 1);
+    count(pair(data, data), length)(input)
+}
+
+fn parse_verbatim_string<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], Data<'a>> {
+    let (input, _) = tag("=")(input)?;
+    map_res(
+        |input| blob(input, limits),
+        |data| match data.split_once(':') {
+            Some((format, data)) if format.len() == 3 => Ok(Data::VerbatimString(format, data)),
+            _ => Err(()),
+        },
+    )(input)
+}
+
+fn parse_aggregate<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    depth: usize,
+) -> IResult<&'a [u8], Data<'a>> {
+    let (input, prefix) = alt((tag("*"), tag("~"), tag(">")))(input)?;
+    let (input, length) = nested(input, limits, depth)?;
+    let (input, data) = count(|input| parse_data_at(input, limits, depth + 1), length)(input)?;
+
+    Ok((
+        input,
+        match prefix {
+            b"*" => Data::Array(data),
+            b"~" => Data::Set(data),
+            _ => Data::Push(data),
+        },
+    ))
+}
+
+fn parse_data_at<'a>(
+    input: &'a [u8],
+    limits: &Limits,
+    depth: usize,
+) -> IResult<&'a [u8], Data<'a>> {
+    let blob = |input| blob(input, limits);
+    let pairs = |input| pairs(input, limits, depth);
+    alt((
+        map(preceded(tag("+"), line), Data::String),
+        map(preceded(tag("-"), line), Data::Error),
+        map(preceded(tag(":"), map_res(line, str::parse)), Data::Integer),
+        map(preceded(tag("$"), blob), Data::BulkString),
+        map(pair(tag("_"), line_ending), |_| Data::Null),
+        map(
+            preceded(tag("#"), terminated(alt((tag("t"), tag("f"))), line_ending)),
+            |data: &[u8]| Data::Boolean(data == b"t"),
+        ),
+        map(preceded(tag(","), map_res(line, str::parse)), Data::Double),
+        map(
+            preceded(
+                tag("("),
+                terminated(
+                    map_res(
+                        recognize(pair(opt(alt((tag("-"), tag("+")))), digit1)),
+                        std::str::from_utf8,
+                    ),
+                    line_ending,
+                ),
+            ),
+            Data::BigNumber,
+        ),
+        map(preceded(tag("!"), blob), Data::BulkError),
+        |input| parse_verbatim_string(input, limits),
+        map(preceded(tag("%"), pairs), Data::Map),
+        map(preceded(tag("|"), pairs), Data::Attribute),
+        |input| parse_aggregate(input, limits, depth),
+    ))(input)
+}
+
+/// Parse data within the given limits
+pub fn parse_data_with<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], Data<'a>> {
+    parse_data_at(input, limits, 0)
+}
+
+/// Parse data within the [default limits](Limits::default())
+pub fn parse_data(input: &[u8]) -> IResult<&[u8], Data<'_>> {
+    parse_data_with(input, &Limits::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data() {
+        assert_eq!(
+            parse_data(":-12\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Integer(-12)))
+        );
+        assert_eq!(
+            parse_data("_\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Null))
+        );
+        assert_eq!(
+            parse_data("#f\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Boolean(false)))
+        );
+        assert_eq!(
+            parse_data(",-inf\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::Double(f64::NEG_INFINITY)))
+        );
+        assert_eq!(
+            parse_data("(-3492890328409238509324850943850943825024385\r\n".as_bytes()),
+            Ok((
+                "".as_bytes(),
+                Data::BigNumber("-3492890328409238509324850943850943825024385")
+            ))
+        );
+        assert_eq!(
+            parse_data("!21\r\nSYNTAX invalid syntax\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::BulkError("SYNTAX invalid syntax")))
+        );
+        assert_eq!(
+            parse_data("=15\r\ntxt:Some string\r\n".as_bytes()),
+            Ok(("".as_bytes(), Data::VerbatimString("txt", "Some string")))
+        );
+        assert_eq!(
+            parse_data("%2\r\n+first\r\n:1\r\n+second\r\n:2\r\n".as_bytes()),
+            Ok((
+                "".as_bytes(),
+                Data::Map(vec![
+                    (Data::String("first"), Data::Integer(1)),
+                    (Data::String("second"), Data::Integer(2)),
+                ])
+            ))
+        );
+        assert_eq!(
+            parse_data(">2\r\n$7\r\nmessage\r\n~1\r\n#t\r\n".as_bytes()),
+            Ok((
+                "".as_bytes(),
+                Data::Push(vec![
+                    Data::BulkString("message"),
+                    Data::Set(vec![Data::Boolean(true)]),
+                ])
+            ))
+        );
+        assert!(matches!(
+            parse_data("%2\r\n+first\r\n".as_bytes()),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let data = Data::Map(vec![
+            (Data::BulkString("proto"), Data::Integer(3)),
+            (Data::BulkString("ratio"), Data::Double(0.5)),
+            (Data::BulkString("info"), Data::VerbatimString("txt", "a:1")),
+            (Data::BulkString("missing"), Data::Null),
+        ]);
+        let encoded = data.as_str().unwrap();
+        assert_eq!(parse_data(encoded.as_bytes()), Ok(("".as_bytes(), data)));
+    }
+
+    #[test]
+    fn test_as_resp2_str() {
+        assert_eq!(Data::Null.as_resp2_str().unwrap(), "$-1\r\n");
+        assert_eq!(
+            Data::Map(vec![(Data::BulkString("proto"), Data::Integer(2))])
+                .as_resp2_str()
+                .unwrap(),
+            "*2\r\n$5\r\nproto\r\n:2\r\n"
+        );
+    }
+}
@@ -1,51 +1,48 @@
 use crate::{
-    command::{Command, SubCommand},
-    protocol::resp2::{
-        self,
-        Data::{Array, BulkString},
-    },
+    command::Command,
+    protocol::{resp2, Limits},
 };
 
-use nom::IResult;
+use nom::{
+    bytes::streaming::tag,
+    error::{Error, ErrorKind},
+    multi::count,
+    IResult,
+};
+use std::borrow::Cow;
 use tracing::debug;
 
-pub fn parse_command(input: &[u8]) -> IResult<&[u8], Command> {
-    let (remaining, data) = resp2::parse_data(input)?;
+/// Parse a RESP2 array of bulk strings into its arguments, a null array has none.
+///   bulk strings are kept as bytes, so binary values do not have to be valid utf8
+pub fn parse_args<'a>(input: &'a [u8], limits: &Limits) -> IResult<&'a [u8], Vec<Cow<'a, [u8]>>> {
+    match resp2::parse_null_array(input) {
+        Ok((input, _)) => return Ok((input, vec![])),
+        Err(nom::Err::Incomplete(needed)) => return Err(nom::Err::Incomplete(needed)),
+        Err(_) => (),
+    }
+
+    let (input, _) = tag("*")(input)?;
+    let (input, length) = resp2::parse_length(input, limits.max_array_len)?;
+    count(
+        |input| {
+            let (input, arg) = resp2::parse_bulk(input, limits)?;
+            Ok((input, Cow::Borrowed(arg)))
+        },
+        length,
+    )(input)
+}
+
+pub fn parse_command(input: &[u8]) -> IResult<&[u8], Command<'_>> {
+    let (remaining, args) = parse_args(input, &Limits::default())?;
 
-    if let Array(arr) = data {
-        match arr[..] {
-            [BulkString("COMMAND"), BulkString("DOCS")] => {
-                Ok((remaining, Command::Command(SubCommand::Docs)))
-            }
-            [BulkString("DBSIZE")] => Ok((remaining, Command::DbSize)),
-            [BulkString("ECHO"), BulkString(data)] => Ok((remaining, Command::Echo(data))),
-            [BulkString("GET"), BulkString(key)] => Ok((remaining, Command::Get(key))),
-            [BulkString("SET"), BulkString(key), BulkString(value)] => {
-                Ok((remaining, Command::Set(key, value.as_bytes())))
-            }
-            [BulkString("KEYS")] => Ok((remaining, Command::Keys(None))),
-            [BulkString("KEYS"), BulkString(pattern)] => {
-                Ok((remaining, Command::Keys(Some(pattern))))
-            }
-            [BulkString("PING")] => Ok((remaining, Command::Ping)),
-            [BulkString("QUIT")] => Ok((remaining, Command::Quit)),
-            _ => {
-                debug!("Failed to parse command: {:?}", arr);
-                Err(nom::Err::Error(nom::error::Error::new(
-                    input,
-                    nom::error::ErrorKind::Tag,
-                )))
-            }
-        }
-    } else {
-        Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Tag,
-        )))
+    debug!(args = ?args, "parsing command");
+    match Command::from_args(args) {
+        Ok(command) => Ok((remaining, command)),
+        Err(_) => Err(nom::Err::Error(Error::new(input, ErrorKind::Tag))),
     }
 }
 
-pub fn try_parse_command(input: &[u8]) -> Option<Command> {
+pub fn try_parse_command(input: &[u8]) -> Option<Command<'_>> {
     match parse_command(input) {
         Ok((_, command)) => Some(command),
         Err(_) => None,
@@ -1,101 +1,193 @@
-use crate::command::Command;
+//! Inline commands, as typed into a telnet session
+//! https://redis.io/docs/reference/protocol-spec/#inline-commands
+use crate::{
+    command::Command,
+    protocol::{too_large, Limits},
+};
 
 use nom::{
-    branch::alt,
-    bytes::streaming::{tag, tag_no_case},
-    character::{complete::line_ending, streaming::alphanumeric1},
-    multi::many0,
-    sequence::terminated,
+    bytes::streaming::{tag, take_until},
+    error::{Error, ErrorKind},
     IResult,
 };
+use std::borrow::Cow;
 
-fn parse_db_size(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("dbsize")(input)?;
-    Ok((input, Command::DbSize))
-}
-
-fn parse_echo(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("echo")(input)?;
-    let (input, _) = tag(" ")(input)?;
-    let (input, message) = alphanumeric1(input)?;
-    Ok((
-        input,
-        Command::Echo(std::str::from_utf8(message).expect("message is valid utf8 string")),
-    ))
-}
-
-fn parse_get(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("get")(input)?;
-    let (input, _) = tag(" ")(input)?;
-    let (input, key) = alphanumeric1(input)?;
-    Ok((
-        input,
-        Command::Get(std::str::from_utf8(key).expect("key is valid utf8 string")),
-    ))
+/// Value of a hex digit
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
 }
 
-fn parse_keys_no_pattern(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("keys")(input)?;
-    Ok((input, Command::Keys(None)))
-}
+/// Split a line into arguments the same way Redis does.
+///   arguments are separated by whitespace and may be wrapped in double quotes, which support
+///   escape sequences such as `\n` and `\x41`, or single quotes, which only support `\'`.
+///   returns `None` when quotes are unbalanced or not followed by whitespace
+pub fn split_args(line: &[u8]) -> Option<Vec<Cow<'_, [u8]>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
 
-fn parse_keys_with_pattern(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("keys")(input)?;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
 
-    let (input, _) = tag(" ")(input)?;
-    let (input, pattern) = alphanumeric1(input)?;
-    Ok((
-        input,
-        Command::Keys(Some(
-            std::str::from_utf8(pattern).expect("pattern is valid utf8 string"),
-        )),
-    ))
-}
+        let arg = match line[i] {
+            b'"' => {
+                let mut arg = Vec::new();
+                i += 1;
+                loop {
+                    match line.get(i..)? {
+                        [b'\\', b'x', hi, lo, ..]
+                            if hex_digit(*hi).and(hex_digit(*lo)).is_some() =>
+                        {
+                            arg.push(hex_digit(*hi)? << 4 | hex_digit(*lo)?);
+                            i += 4;
+                        }
+                        [b'\\', c, ..] => {
+                            arg.push(match c {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                c => *c,
+                            });
+                            i += 2;
+                        }
+                        [b'"', ..] => {
+                            i += 1;
+                            break;
+                        }
+                        [c, ..] => {
+                            arg.push(*c);
+                            i += 1;
+                        }
+                        [] => return None,
+                    }
+                }
+                Cow::Owned(arg)
+            }
+            b'\'' => {
+                let mut arg = Vec::new();
+                i += 1;
+                loop {
+                    match line.get(i..)? {
+                        [b'\\', b'\'', ..] => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        [b'\'', ..] => {
+                            i += 1;
+                            break;
+                        }
+                        [c, ..] => {
+                            arg.push(*c);
+                            i += 1;
+                        }
+                        [] => return None,
+                    }
+                }
+                Cow::Owned(arg)
+            }
+            _ => {
+                let start = i;
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                Cow::Borrowed(&line[start..i])
+            }
+        };
 
-fn parse_set(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("set")(input)?;
-    let (input, _) = tag(" ")(input)?;
-    let (input, key) = alphanumeric1(input)?;
-    let (input, _) = tag(" ")(input)?;
-    let (input, value) = alphanumeric1(input)?;
-    Ok((
-        input,
-        Command::Set(
-            std::str::from_utf8(key).expect("key is valid utf8 string"),
-            value,
-        ),
-    ))
+        // A closing quote must end the argument
+        if i < line.len() && !line[i].is_ascii_whitespace() {
+            return None;
+        }
+        args.push(arg);
+    }
 }
 
-fn parse_ping(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("ping")(input)?;
-    Ok((input, Command::Ping))
-}
+/// Parse a single inline command line into its arguments, blank lines are skipped
+pub fn parse_args<'a>(
+    mut input: &'a [u8],
+    limits: &Limits,
+) -> IResult<&'a [u8], Vec<Cow<'a, [u8]>>> {
+    loop {
+        let (remaining, line) = match take_until("\n")(input) {
+            Err(nom::Err::Incomplete(_)) if input.len() > limits.max_inline_len => {
+                return Err(too_large(input))
+            }
+            result => result?,
+        };
+        if line.len() > limits.max_inline_len {
+            return Err(too_large(input));
+        }
+        let (remaining, _) = tag("\n")(remaining)?;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
 
-fn parse_quit(input: &[u8]) -> IResult<&[u8], Command> {
-    let (input, _) = tag_no_case("quit")(input)?;
-    Ok((input, Command::Quit))
+        match split_args(line) {
+            Some(args) if args.is_empty() => input = remaining,
+            Some(args) => return Ok((remaining, args)),
+            None => return Err(nom::Err::Error(Error::new(input, ErrorKind::Verify))),
+        }
+    }
 }
 
-pub fn parse_command(input: &[u8]) -> IResult<&[u8], Command> {
-    terminated(
-        alt((
-            parse_db_size,
-            parse_get,
-            parse_echo,
-            parse_keys_with_pattern,
-            parse_keys_no_pattern,
-            parse_set,
-            parse_ping,
-            parse_quit,
-        )),
-        many0(line_ending),
-    )(input)
+pub fn parse_command(input: &[u8]) -> IResult<&[u8], Command<'_>> {
+    let (remaining, args) = parse_args(input, &Limits::default())?;
+    match Command::from_args(args) {
+        Ok(command) => Ok((remaining, command)),
+        Err(_) => Err(nom::Err::Error(Error::new(input, ErrorKind::Tag))),
+    }
 }
 
-pub fn try_parse_command(input: &[u8]) -> Option<Command> {
+pub fn try_parse_command(input: &[u8]) -> Option<Command<'_>> {
     match parse_command(input) {
         Ok((_, command)) => Some(command),
         Err(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args(br#"SET user:1 hello-world"#).unwrap(),
+            vec![&b"SET"[..], b"user:1", b"hello-world"]
+        );
+        assert_eq!(
+            split_args(br#"  ECHO "hi there" 'it\'s' "\x41\n\"" "" "#).unwrap(),
+            vec![&b"ECHO"[..], b"hi there", b"it's", b"A\n\"", b""]
+        );
+        assert_eq!(split_args(br#"ECHO "unbalanced"#), None);
+        assert_eq!(split_args(br#"ECHO "a"b"#), None);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(b"\r\nset key \"two words\"\r\nPING"),
+            Ok((
+                &b"PING"[..],
+                Command::Set("key".into(), b"two words"[..].into())
+            ))
+        );
+        assert!(matches!(
+            parse_command(b"PING"),
+            Err(nom::Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_command(b"FLUSHDB\r\n"),
+            Err(nom::Err::Error(_))
+        ));
+    }
+}